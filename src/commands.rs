@@ -0,0 +1,791 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use teloxide::{
+    prelude::*,
+    sugar::request::RequestReplyExt,
+    types::{InputFile, Message},
+    utils::command::BotCommands,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    DOWNLOAD_DIR, LAST_FILES,
+    config::{self, MediaType, RUNTIME_CONFIG},
+    is_sidecar, read_sidecar, safe_file_name, sidecar_paths,
+    thumbnail::thumbnail_path,
+    topic_id,
+};
+
+/// Users allowed to run admin commands, comma separated. Nobody is an admin when unset.
+static ADMIN_USER_IDS: Lazy<Vec<UserId>> = Lazy::new(|| {
+    std::env::var("ADMIN_USER_IDS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.trim().parse().ok())
+        .map(UserId)
+        .collect()
+});
+
+/// How long a computed archive size is reused before walking the tree again.
+const SIZE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Bot API upload limits for documents and photos.
+pub const MAX_UPLOAD_BYTES: u64 = 50 * 1024 * 1024;
+const MAX_PHOTO_UPLOAD_BYTES: u64 = 10 * 1024 * 1024;
+
+static SIZE_CACHE: Lazy<Mutex<Option<(Instant, ArchiveSize)>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "lowercase", description = "支持的命令：")]
+pub enum Command {
+    #[command(description = "显示帮助")]
+    Help,
+    #[command(description = "显示存档占用空间，/size detail 按日期列出")]
+    Size(String),
+    #[command(description = "取回本聊天存档的文件，/get <文件名或文件ID>")]
+    Get(String),
+    #[command(description = "显示下载的媒体类型，管理员可用 /types <photo|video> 切换")]
+    Types(String),
+    #[command(description = "重命名本聊天最近存档的文件，/rename <新文件名>")]
+    Rename(String),
+    #[command(description = "把本次会话发送的文件打包发回")]
+    Zipme,
+    #[command(description = "允许存档某个聊天或恢复已暂停的聊天（管理员），/allow <chat_id>")]
+    Allow(String),
+    #[command(description = "停止存档某个聊天（管理员），/deny <chat_id>")]
+    Deny(String),
+    #[command(description = "显示允许存档和已暂停的聊天（管理员）")]
+    Allowlist,
+    #[command(description = "暂停所有下载（管理员）")]
+    GlobalPause,
+    #[command(description = "恢复所有下载（管理员）")]
+    GlobalResume,
+    #[command(description = "检查各组件状态（管理员）")]
+    Diag,
+    #[command(description = "显示生效的配置及本聊天的设置（管理员）")]
+    Config,
+    #[command(description = "清理超过指定天数的存档（管理员），/cleanup [天数] confirm")]
+    Cleanup(String),
+    #[command(description = "为某天的存档生成缩略图（管理员），/thumbs [YYYY-MM-DD]")]
+    Thumbs(String),
+    #[command(description = "显示各聊天每小时的下载数（管理员），/heatmap reset 清空")]
+    Heatmap(String),
+}
+
+#[derive(Clone, Default)]
+struct ArchiveSize {
+    total: u64,
+    /// Bytes per top-level folder of the download roots, i.e. per day.
+    dates: BTreeMap<String, u64>,
+}
+
+pub async fn handle_command(bot: Arc<Bot>, msg: Message, cmd: Command) -> ResponseResult<()> {
+    log::info!(
+        "Received command from chat {}: {:?}",
+        msg.chat.id,
+        msg.text()
+    );
+    let response = match cmd {
+        Command::Help => Command::descriptions().to_string(),
+        Command::Size(arg) => match archive_size().await {
+            Ok(size) => format_size_reply(&size, arg.trim() == "detail"),
+            Err(e) => {
+                log::warn!("Compute archive size error: {:?}", e);
+                String::from("统计存档大小失败")
+            }
+        },
+        Command::Get(name) => match send_archived_file(&bot, &msg, name.trim()).await? {
+            Some(response) => response,
+            None => return Ok(()),
+        },
+        Command::Types(arg) => types_reply(arg.trim(), is_admin(&msg)).await,
+        Command::Rename(name) => rename_reply(msg.chat.id, name.trim()).await,
+        Command::Zipme => crate::zipme::send_session(&bot, &msg).await?,
+        Command::Allow(_) | Command::Deny(_) | Command::Allowlist if !is_admin(&msg) => {
+            String::from("仅管理员可用")
+        }
+        Command::Allow(id) => allowlist_reply(Some((id.trim(), true))).await,
+        Command::Deny(id) => allowlist_reply(Some((id.trim(), false))).await,
+        Command::Allowlist => allowlist_reply(None).await,
+        Command::GlobalPause | Command::GlobalResume if !is_admin(&msg) => {
+            String::from("仅管理员可用")
+        }
+        Command::GlobalPause => {
+            crate::PAUSED.store(true, Ordering::SeqCst);
+            log::info!("Downloads paused by {:?}", msg.from.as_ref().map(|u| u.id));
+            String::from("已暂停所有下载，期间收到的消息不会存档")
+        }
+        Command::GlobalResume => {
+            crate::PAUSED.store(false, Ordering::SeqCst);
+            log::info!("Downloads resumed by {:?}", msg.from.as_ref().map(|u| u.id));
+            String::from("已恢复下载")
+        }
+        Command::Diag if is_admin(&msg) => crate::diag::report(&bot).await,
+        Command::Diag => String::from("仅管理员可用"),
+        Command::Config if is_admin(&msg) => config_reply(Some(msg.chat.id)).await,
+        Command::Config => String::from("仅管理员可用"),
+        Command::Cleanup(arg) if is_admin(&msg) => cleanup_reply(arg.trim()).await,
+        Command::Cleanup(_) => String::from("仅管理员可用"),
+        Command::Thumbs(arg) if is_admin(&msg) => thumbs_reply(arg.trim()).await,
+        Command::Thumbs(_) => String::from("仅管理员可用"),
+        Command::Heatmap(arg) if is_admin(&msg) => crate::heatmap::reply(arg.trim()).await,
+        Command::Heatmap(_) => String::from("仅管理员可用"),
+    };
+    let mut request = bot.send_message(msg.chat.id, response).reply_to(msg.id);
+    request.message_thread_id = topic_id(&msg);
+    request.await?;
+    Ok(())
+}
+
+fn is_admin(msg: &Message) -> bool {
+    msg.from
+        .as_ref()
+        .is_some_and(|user| ADMIN_USER_IDS.contains(&user.id))
+}
+
+/// Shows the enabled media types, toggling `arg` first when given.
+async fn types_reply(arg: &str, admin: bool) -> String {
+    let mut config = RUNTIME_CONFIG.write().await;
+    if !arg.is_empty() {
+        if !admin {
+            return String::from("仅管理员可修改下载类型");
+        }
+        let Ok(media_type) = arg.parse::<MediaType>() else {
+            return format!("未知类型: {}，可选: photo, video", arg);
+        };
+        let types = config.download_types.get_or_insert_with(Default::default);
+        if !types.remove(&media_type) {
+            types.insert(media_type);
+        }
+        log::info!("Download types changed to {:?}", types);
+        if let Err(e) = config::save(&config).await {
+            log::warn!("{:?}", e);
+        }
+    }
+    let enabled = MediaType::ALL
+        .iter()
+        .filter(|t| config.downloads(**t))
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    if enabled.is_empty() {
+        String::from("已启用类型: 无")
+    } else {
+        format!("已启用类型: {}", enabled.join(", "))
+    }
+}
+
+/// Shows the chat allowlist and the paused chats, adding or removing the chat id in
+/// `change` first. Allowing a chat also resumes it if it was paused.
+async fn allowlist_reply(change: Option<(&str, bool)>) -> String {
+    let mut config = RUNTIME_CONFIG.write().await;
+    if let Some((id, allow)) = change {
+        let Ok(id) = id.parse::<i64>() else {
+            return format!("无效的聊天 ID: {}", id);
+        };
+        let resumed = allow && config.paused_chat_ids.remove(&id);
+        match (config.allowed_chat_ids.as_mut(), allow) {
+            (None, false) => return String::from("当前允许所有聊天，请先用 /allow 添加聊天"),
+            (None, true) if resumed => {}
+            (None, true) => config.allowed_chat_ids = Some([id].into()),
+            (Some(ids), true) => {
+                ids.insert(id);
+            }
+            (Some(ids), false) => {
+                ids.remove(&id);
+            }
+        }
+        log::info!("Allowed chats changed to {:?}", config.allowed_chat_ids);
+        if let Err(e) = config::save(&config).await {
+            log::warn!("{:?}", e);
+        }
+    }
+    let join = |ids: &BTreeSet<i64>| {
+        ids.iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut reply = match &config.allowed_chat_ids {
+        None => String::from("允许存档: 所有聊天"),
+        Some(ids) if ids.is_empty() => String::from("允许存档: 无"),
+        Some(ids) => format!("允许存档: {}", join(ids)),
+    };
+    if !config.paused_chat_ids.is_empty() {
+        reply.push_str(&format!(
+            "\n已暂停（机器人被移出）: {}，/allow <chat_id> 恢复",
+            join(&config.paused_chat_ids)
+        ));
+    }
+    reply
+}
+
+/// Removes archives past the given number of days, or past the configured retention
+/// periods. Without `confirm` it only reports what would be removed.
+async fn cleanup_reply(arg: &str) -> String {
+    let mut days = None;
+    let mut confirm = false;
+    for word in arg.split_whitespace() {
+        match word {
+            "confirm" => confirm = true,
+            word => match word.parse() {
+                Ok(value) => days = Some(value),
+                Err(_) => return String::from("用法: /cleanup [天数] confirm"),
+            },
+        }
+    }
+    if days.is_none() && !crate::retention::configured() {
+        return String::from("未设置 RETENTION_DAYS，请指定天数: /cleanup <天数>");
+    }
+
+    let roots = RUNTIME_CONFIG.read().await.download_roots();
+    let result = tokio::task::spawn_blocking(move || {
+        let expired = crate::retention::expired(roots, days)?;
+        let freed = if confirm {
+            crate::retention::remove(&expired)?
+        } else {
+            0
+        };
+        std::io::Result::Ok((expired, freed))
+    })
+    .await;
+    let (expired, freed) = match result.map_err(anyhow::Error::from).and_then(|r| Ok(r?)) {
+        Ok(result) => result,
+        Err(e) => {
+            log::warn!("Cleanup archive error: {:?}", e);
+            return String::from("清理存档失败");
+        }
+    };
+    if expired.items == 0 {
+        return String::from("没有超过保留期限的存档");
+    }
+    if !confirm {
+        let command = match days {
+            Some(days) => format!("/cleanup {} confirm", days),
+            None => String::from("/cleanup confirm"),
+        };
+        return format!(
+            "将删除 {} 项，共 {}，发送 {} 确认",
+            expired.items,
+            human_size(expired.bytes),
+            command
+        );
+    }
+    *SIZE_CACHE.lock().await = None;
+    format!("已删除 {} 项，释放 {}", expired.items, human_size(freed))
+}
+
+/// Generates thumbnails for the day folder `arg`, today when empty.
+async fn thumbs_reply(arg: &str) -> String {
+    let date = if arg.is_empty() {
+        crate::today()
+    } else {
+        match chrono::NaiveDate::parse_from_str(arg, "%Y-%m-%d") {
+            Ok(date) => date,
+            Err(_) => return String::from("用法: /thumbs [YYYY-MM-DD]"),
+        }
+    };
+    let day = date.format("%Y-%m-%d").to_string();
+    let dirs: Vec<PathBuf> = RUNTIME_CONFIG
+        .read()
+        .await
+        .download_roots()
+        .into_iter()
+        .map(|root| root.join(&day))
+        .filter(|dir| dir.is_dir())
+        .collect();
+    if dirs.is_empty() {
+        return format!("{} 没有存档", date);
+    }
+    let Ok(_permit) = crate::HOOK_PERMITS.acquire().await else {
+        return String::from("生成缩略图失败");
+    };
+    let result = tokio::task::spawn_blocking(move || {
+        let mut stats = crate::thumbnail::ThumbnailStats::default();
+        for dir in dirs {
+            let day = crate::thumbnail::generate(&dir)?;
+            stats.created += day.created;
+            stats.skipped += day.skipped;
+            stats.failed += day.failed;
+        }
+        std::io::Result::Ok(stats)
+    })
+    .await;
+    match result.map_err(anyhow::Error::from).and_then(|r| Ok(r?)) {
+        Ok(stats) => format!(
+            "已生成 {} 个缩略图，跳过已有 {} 个，失败 {} 个",
+            stats.created, stats.skipped, stats.failed
+        ),
+        Err(e) => {
+            log::warn!("Generate thumbnails error: {:?}", e);
+            String::from("生成缩略图失败")
+        }
+    }
+}
+
+/// Renames the latest file archived from `chat_id`, together with its sidecar and
+/// thumbnail. The extension is kept when `name` has none. Sidecar names are refused,
+/// since the file would then be taken for metadata.
+async fn rename_reply(chat_id: ChatId, name: &str) -> String {
+    if name.is_empty() {
+        return String::from("用法: /rename <新文件名>");
+    }
+    let mut last_files = LAST_FILES.lock().await;
+    let Some(path) = last_files.get(&chat_id).cloned() else {
+        return String::from("本聊天还没有存档文件");
+    };
+    let mut new_name = safe_file_name(name);
+    if Path::new(&new_name).extension().is_none()
+        && let Some(extension) = path.extension()
+    {
+        new_name = format!("{}.{}", new_name, extension.to_string_lossy());
+    }
+    let new_path = path.with_file_name(&new_name);
+    if is_sidecar(&new_path) {
+        return format!("不能重命名为元数据文件: {}", new_name);
+    }
+    if new_path == path {
+        return format!("文件名未变: {}", new_name);
+    }
+    let related = sidecar_paths(&path)
+        .into_iter()
+        .chain([thumbnail_path(&path)])
+        .collect::<Vec<_>>();
+    let new_related = sidecar_paths(&new_path)
+        .into_iter()
+        .chain([thumbnail_path(&new_path)])
+        .collect::<Vec<_>>();
+    if new_path.exists() || new_related.iter().any(|p| p.exists()) {
+        return format!("文件已存在: {}", new_name);
+    }
+
+    let renamed = async {
+        tokio::fs::rename(&path, &new_path).await?;
+        for (old, new) in related.iter().zip(&new_related) {
+            if old.exists() {
+                tokio::fs::rename(old, new).await?;
+            }
+        }
+        std::io::Result::Ok(())
+    };
+    if let Err(e) = renamed.await {
+        log::warn!("Rename {:?} to {:?} error: {:?}", &path, &new_path, e);
+        return String::from("重命名失败");
+    }
+    log::info!("Renamed {:?} to {:?}", &path, &new_path);
+    let roots = RUNTIME_CONFIG.read().await.download_roots();
+    let reply = format!(
+        "已重命名为: {}",
+        roots
+            .iter()
+            .find_map(|root| new_path.strip_prefix(root).ok())
+            .unwrap_or(&new_path)
+            .display()
+    );
+    last_files.insert(chat_id, new_path);
+    reply
+}
+
+/// Lists the resolved settings. Secrets are only reported as set or unset. Per-chat
+/// settings are only reported for `chat_id`, the chat /config was sent in; /allowlist
+/// lists every chat.
+pub async fn config_reply(chat_id: Option<ChatId>) -> String {
+    let secret = |key: &str| {
+        if std::env::var(key).is_ok() {
+            String::from("<已设置>")
+        } else {
+            String::from("<未设置>")
+        }
+    };
+    let (download_types, chat) = {
+        let config = RUNTIME_CONFIG.read().await;
+        let chat = chat_id.map(|chat_id| {
+            (
+                chat_id,
+                config.download_dir(chat_id.0),
+                config
+                    .allowed_chat_ids
+                    .as_ref()
+                    .is_none_or(|ids| ids.contains(&chat_id.0)),
+                config.paused_chat_ids.contains(&chat_id.0),
+            )
+        });
+        (config.download_types.clone(), chat)
+    };
+    let mut settings = vec![
+        ("TELOXIDE_TOKEN", secret("TELOXIDE_TOKEN")),
+        ("PAUSED", crate::PAUSED.load(Ordering::SeqCst).to_string()),
+        ("DOWNLOAD_DIR", DOWNLOAD_DIR.display().to_string()),
+        (
+            "STAGING_DIR",
+            crate::crypto::STAGING_DIR.display().to_string(),
+        ),
+        ("CONFIG_FILE", config::CONFIG_FILE.display().to_string()),
+        (
+            "DOWNLOAD_TYPES",
+            format!("{:?}", download_types.unwrap_or_default()),
+        ),
+        (
+            "ARCHIVE_UNKNOWN_MEDIA",
+            crate::ARCHIVE_UNKNOWN_MEDIA.to_string(),
+        ),
+        ("UPDATE_MODE", crate::UPDATE_MODE.clone()),
+        ("WEBHOOK_LISTEN", format!("{:?}", *crate::WEBHOOK_LISTEN)),
+        ("WEBHOOK_URL", format!("{:?}", *crate::WEBHOOK_URL)),
+        ("STARTUP_NOTIFY", crate::STARTUP_NOTIFY.to_string()),
+        (
+            "MEMBERSHIP_NOTIFY",
+            crate::membership::MEMBERSHIP_NOTIFY.to_string(),
+        ),
+        (
+            "MEMBERSHIP_PAUSE_REMOVED",
+            crate::membership::MEMBERSHIP_PAUSE_REMOVED.to_string(),
+        ),
+        ("ADMIN_USER_IDS", format!("{:?}", *ADMIN_USER_IDS)),
+        ("ADMIN_CHAT_ID", format!("{:?}", *crate::ADMIN_CHAT_ID)),
+        (
+            "SUMMARY_TARGET",
+            format!("{:?}", std::env::var("SUMMARY_TARGET").ok()),
+        ),
+        (
+            "SUMMARY_PARSE_MODE",
+            format!("{:?}", *crate::SUMMARY_PARSE_MODE),
+        ),
+        (
+            "MAX_ITEMS_PER_REPLY",
+            format!("{:?}", *crate::MAX_ITEMS_PER_REPLY),
+        ),
+        (
+            "MAX_RETRY_AFTER_SECS",
+            crate::MAX_RETRY_AFTER_SECS.to_string(),
+        ),
+        (
+            "GET_FILE_ATTEMPTS",
+            crate::GET_FILE_RETRY.attempts.to_string(),
+        ),
+        (
+            "GET_FILE_BACKOFF_MS",
+            crate::GET_FILE_RETRY.backoff.as_millis().to_string(),
+        ),
+        (
+            "DOWNLOAD_ATTEMPTS",
+            crate::DOWNLOAD_RETRY.attempts.to_string(),
+        ),
+        (
+            "DOWNLOAD_BACKOFF_MS",
+            crate::DOWNLOAD_RETRY.backoff.as_millis().to_string(),
+        ),
+        (
+            "ALBUM_REPLY_MODE",
+            String::from(if *crate::ALBUM_REPLY_COMPACT {
+                "compact"
+            } else {
+                "detailed"
+            }),
+        ),
+        ("TRUNCATED_RETRIES", crate::TRUNCATED_RETRIES.to_string()),
+        ("MIN_FREE_INODES", format!("{:?}", *crate::MIN_FREE_INODES)),
+        ("MAX_INFLIGHT", format!("{:?}", *crate::MAX_INFLIGHT)),
+        ("ALBUM_TIMEOUT_SECS", crate::ALBUM_TIMEOUT_SECS.to_string()),
+        (
+            "REACT_INSTEAD_OF_REPLY",
+            crate::REACT_INSTEAD_OF_REPLY.to_string(),
+        ),
+        ("REACTION_EMOJI", crate::REACTION_EMOJI.to_string()),
+        ("REPLY_ON_FAILURE", crate::REPLY_ON_FAILURE.to_string()),
+        ("TIMEZONE", format!("{:?}", *crate::TIMEZONE)),
+        ("GROUP_BY_TOPIC", crate::GROUP_BY_TOPIC.to_string()),
+        ("GROUP_BY_SENDER", format!("{:?}", *crate::GROUP_BY_SENDER)),
+        (
+            "ALLOWED_USER_IDS",
+            format!("{:?}", *crate::ALLOWED_USER_IDS),
+        ),
+        ("ALLOW_CHAT_SENDERS", crate::ALLOW_CHAT_SENDERS.to_string()),
+        (
+            "ARCHIVE_COMMAND_MEDIA",
+            crate::ARCHIVE_COMMAND_MEDIA.to_string(),
+        ),
+        ("SAVE_REPLY_CONTEXT", crate::SAVE_REPLY_CONTEXT.to_string()),
+        ("EMBED_MESSAGE_LINK", crate::EMBED_MESSAGE_LINK.to_string()),
+        ("VERIFY_MEDIA_TYPE", crate::VERIFY_MEDIA_TYPE.to_string()),
+        (
+            "CAPTION_FILTER_REGEX",
+            format!(
+                "{:?}",
+                crate::CAPTION_FILTER_REGEX
+                    .as_ref()
+                    .ok()
+                    .and_then(|r| r.as_ref())
+                    .map(|r| r.as_str())
+            ),
+        ),
+        (
+            "CAPTION_FILTER_INCLUDE_EMPTY",
+            crate::CAPTION_FILTER_INCLUDE_EMPTY.to_string(),
+        ),
+        ("SKIP_FORWARDS", crate::SKIP_FORWARDS.to_string()),
+        (
+            "PRESERVE_CAPTION_LINKS",
+            crate::PRESERVE_CAPTION_LINKS.to_string(),
+        ),
+        (
+            "ARCHIVE_SERVICE_MESSAGES",
+            crate::ARCHIVE_SERVICE_MESSAGES.to_string(),
+        ),
+        (
+            "RETENTION_DAYS",
+            format!("{:?}", *crate::retention::RETENTION_DAYS),
+        ),
+        (
+            "RETENTION_PHOTO_DAYS",
+            format!("{:?}", *crate::retention::RETENTION_PHOTO_DAYS),
+        ),
+        (
+            "RETENTION_VIDEO_DAYS",
+            format!("{:?}", *crate::retention::RETENTION_VIDEO_DAYS),
+        ),
+        (
+            "THUMBNAIL_SIZE",
+            crate::thumbnail::THUMBNAIL_SIZE.to_string(),
+        ),
+        (
+            "HONOR_RETRACTIONS",
+            crate::retraction::HONOR_RETRACTIONS.to_string(),
+        ),
+        (
+            "UPDATE_EDITED_CAPTIONS",
+            crate::retraction::UPDATE_EDITED_CAPTIONS.to_string(),
+        ),
+        ("REDACT_FIELDS", format!("{:?}", *crate::REDACT_FIELDS)),
+        ("HOOK_CONCURRENCY", crate::HOOK_CONCURRENCY.to_string()),
+        (
+            "MAX_METADATA_BYTES",
+            format!("{:?}", *crate::MAX_METADATA_BYTES),
+        ),
+        ("METADATA_PRETTY", crate::METADATA_PRETTY.to_string()),
+        ("ZIP_SESSIONS", crate::zipme::ZIP_SESSIONS.to_string()),
+        ("ZIP_DISCARD", crate::zipme::ZIP_DISCARD.to_string()),
+        ("COMPRESS_METADATA", crate::COMPRESS_METADATA.to_string()),
+        ("FSYNC_WRITES", crate::FSYNC_WRITES.to_string()),
+        ("ENCRYPTION_KEY", secret("ENCRYPTION_KEY")),
+        ("LOG_FILE", format!("{:?}", std::env::var("LOG_FILE").ok())),
+    ];
+    if let Some((chat_id, dir, allowed, paused)) = chat {
+        settings.extend([
+            ("CHAT_ID", chat_id.to_string()),
+            ("CHAT_DOWNLOAD_DIR", dir.display().to_string()),
+            ("CHAT_ALLOWED", allowed.to_string()),
+            ("CHAT_PAUSED", paused.to_string()),
+        ]);
+    }
+    settings.extend(crate::watermark::settings());
+    settings.extend(crate::transcode::settings());
+    settings.extend(crate::priority::settings());
+    settings.extend(crate::throttle::settings());
+    settings.extend(crate::heatmap::settings());
+    settings
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Sends back a file archived from this chat. Returns the reply text when nothing was sent.
+async fn send_archived_file(
+    bot: &Bot,
+    msg: &Message,
+    name: &str,
+) -> ResponseResult<Option<String>> {
+    if name.is_empty() {
+        return Ok(Some(String::from("用法: /get <文件名或文件ID>")));
+    }
+    let (query, chat_id) = (name.to_owned(), msg.chat.id);
+    let root = RUNTIME_CONFIG.read().await.download_dir(chat_id.0);
+    let found =
+        tokio::task::spawn_blocking(move || find_archived_file(&root, &query, chat_id)).await;
+    let Ok(Some((path, sidecar))) = found else {
+        return Ok(Some(format!("未找到文件: {}", name)));
+    };
+    if sidecar["archive"].get("encryption").is_some() {
+        return Ok(Some(String::from("文件已加密存储，无法发送")));
+    }
+    let size = tokio::fs::metadata(&path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(u64::MAX);
+    if size > MAX_UPLOAD_BYTES {
+        return Ok(Some(String::from("文件超过 Telegram 50MB 上传限制")));
+    }
+
+    log::info!("Send archived file {:?} to chat {}", &path, chat_id);
+    let file = InputFile::file(&path);
+    if sidecar.get("photo").is_some() && size <= MAX_PHOTO_UPLOAD_BYTES {
+        let mut request = bot.send_photo(chat_id, file).reply_to(msg.id);
+        request.message_thread_id = topic_id(msg);
+        request.await?;
+    } else {
+        let mut request = bot.send_document(chat_id, file).reply_to(msg.id);
+        request.message_thread_id = topic_id(msg);
+        request.await?;
+    }
+    Ok(None)
+}
+
+/// Finds an archived file whose name contains `query` and whose sidecar shows it came
+/// from `chat_id`, so chats can only retrieve their own files.
+fn find_archived_file(
+    dir: &Path,
+    query: &str,
+    chat_id: ChatId,
+) -> Option<(PathBuf, serde_json::Value)> {
+    for entry in std::fs::read_dir(dir).ok()?.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if let Some(found) = find_archived_file(&path, query, chat_id) {
+                return Some(found);
+            }
+            continue;
+        }
+        if is_sidecar(&path) || !entry.file_name().to_string_lossy().contains(query) {
+            continue;
+        }
+        if let Some(sidecar) = read_sidecar(&path)
+            && sidecar["chat"]["id"].as_i64() == Some(chat_id.0)
+        {
+            return Some((path, sidecar));
+        }
+    }
+    None
+}
+
+/// Returns the archive size, walking the download roots only when the cached value is
+/// stale.
+async fn archive_size() -> anyhow::Result<ArchiveSize> {
+    let mut cache = SIZE_CACHE.lock().await;
+    if let Some((computed_at, size)) = cache.as_ref()
+        && computed_at.elapsed() < SIZE_CACHE_TTL
+    {
+        return Ok(size.clone());
+    }
+    let roots = RUNTIME_CONFIG.read().await.download_roots();
+    let size = tokio::task::spawn_blocking(move || {
+        let mut size = ArchiveSize::default();
+        for root in roots {
+            walk_archive(&root, &mut size)?;
+        }
+        std::io::Result::Ok(size)
+    })
+    .await??;
+    *cache = Some((Instant::now(), size.clone()));
+    Ok(size)
+}
+
+/// Adds the files under `root` to `size`. Missing per-chat folders count as empty.
+fn walk_archive(root: &Path, size: &mut ArchiveSize) -> std::io::Result<()> {
+    let entries = match std::fs::read_dir(root) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && root != DOWNLOAD_DIR.as_path() => {
+            return Ok(());
+        }
+        entries => entries?,
+    };
+    for entry in entries {
+        let entry = entry?;
+        let bytes = dir_size(&entry.path())?;
+        size.total += bytes;
+        if entry.file_type()?.is_dir() {
+            *size
+                .dates
+                .entry(entry.file_name().to_string_lossy().into_owned())
+                .or_default() += bytes;
+        }
+    }
+    Ok(())
+}
+
+pub fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut total = 0;
+    for entry in std::fs::read_dir(path)? {
+        total += dir_size(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+fn format_size_reply(size: &ArchiveSize, detail: bool) -> String {
+    let mut lines = vec![format!("存档总大小: {}", human_size(size.total))];
+    if detail {
+        lines.extend(
+            size.dates
+                .iter()
+                .map(|(date, bytes)| format!("{}: {}", date, human_size(*bytes))),
+        );
+    }
+    lines.join("\n")
+}
+
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rename_moves_sidecar_and_thumbnail() {
+        let dir = crate::testing::TempDir::new("rename");
+        std::fs::create_dir_all(dir.join("thumbnails")).unwrap();
+        let path = dir.join("photo_u1_5.jpg");
+        for file in [
+            path.clone(),
+            sidecar_paths(&path)[0].clone(),
+            thumbnail_path(&path),
+        ] {
+            std::fs::write(file, b"data").unwrap();
+        }
+        let chat_id = ChatId(-424242);
+        LAST_FILES.lock().await.insert(chat_id, path.clone());
+
+        let refused = [
+            rename_reply(chat_id, "notes.json").await,
+            rename_reply(chat_id, "notes.json.gz").await,
+        ];
+        let renamed = rename_reply(chat_id, "beach").await;
+        let new_path = dir.join("beach.jpg");
+        let exists = [
+            new_path.exists(),
+            sidecar_paths(&new_path)[0].exists(),
+            thumbnail_path(&new_path).exists(),
+            path.exists(),
+        ];
+        let last_file = LAST_FILES.lock().await.remove(&chat_id);
+
+        assert!(
+            refused
+                .iter()
+                .all(|reply| reply.starts_with("不能重命名为元数据文件"))
+        );
+        assert!(renamed.starts_with("已重命名为"));
+        assert_eq!(exists, [true, true, true, false]);
+        assert_eq!(last_file, Some(new_path));
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fmt,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::DOWNLOAD_DIR;
+
+/// Where settings changed through commands are persisted, so they survive restarts.
+pub static CONFIG_FILE: Lazy<PathBuf> = Lazy::new(|| {
+    env::var("CONFIG_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| DOWNLOAD_DIR.join(".eatlink_config.json"))
+});
+
+/// Settings adjustable at runtime. Values saved in CONFIG_FILE win over the environment.
+/// Only values changed through commands are saved there.
+pub static RUNTIME_CONFIG: Lazy<RwLock<RuntimeConfig>> = Lazy::new(|| {
+    let mut config = std::fs::read(&*CONFIG_FILE)
+        .ok()
+        .and_then(|data| {
+            serde_json::from_slice::<RuntimeConfig>(&data)
+                .inspect_err(|e| log::warn!("Parse {:?} error: {:?}", &*CONFIG_FILE, e))
+                .ok()
+        })
+        .unwrap_or_default();
+    if let Some(dirs) = config.chat_download_dirs.as_mut() {
+        absolute_only(dirs);
+    }
+    let from_env = FROM_ENV.clone();
+    for (key, overridden) in [
+        (
+            "DOWNLOAD_TYPES",
+            changed(&config.download_types, &from_env.download_types).is_some(),
+        ),
+        (
+            "CHAT_DOWNLOAD_DIRS",
+            changed(&config.chat_download_dirs, &from_env.chat_download_dirs).is_some(),
+        ),
+        (
+            "ALLOWED_CHAT_IDS",
+            changed(&config.allowed_chat_ids, &from_env.allowed_chat_ids).is_some(),
+        ),
+    ] {
+        if overridden && env::var(key).is_ok() {
+            log::warn!("{} differs from {:?}, which wins", key, &*CONFIG_FILE);
+        }
+    }
+    config.download_types = config.download_types.or(from_env.download_types);
+    config.chat_download_dirs = config.chat_download_dirs.or(from_env.chat_download_dirs);
+    config.allowed_chat_ids = config.allowed_chat_ids.or(from_env.allowed_chat_ids);
+    RwLock::new(config)
+});
+
+/// The settings as given by the environment alone, to tell which saved values differ.
+static FROM_ENV: Lazy<RuntimeConfig> = Lazy::new(|| RuntimeConfig {
+    download_types: Some(download_types_from_env()),
+    chat_download_dirs: Some(chat_download_dirs_from_env()),
+    allowed_chat_ids: allowed_chat_ids_from_env(),
+    paused_chat_ids: BTreeSet::new(),
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaType {
+    Photo,
+    Video,
+}
+
+impl MediaType {
+    pub const ALL: [MediaType; 2] = [MediaType::Photo, MediaType::Video];
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaType::Photo => write!(f, "photo"),
+            MediaType::Video => write!(f, "video"),
+        }
+    }
+}
+
+impl FromStr for MediaType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "photo" => Ok(MediaType::Photo),
+            "video" => Ok(MediaType::Video),
+            _ => anyhow::bail!("Unknown media type: {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RuntimeConfig {
+    /// Media types that are downloaded, initialised from DOWNLOAD_TYPES.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_types: Option<BTreeSet<MediaType>>,
+    /// Absolute folders that replace DOWNLOAD_DIR for some chats, initialised from
+    /// CHAT_DOWNLOAD_DIRS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_download_dirs: Option<BTreeMap<i64, PathBuf>>,
+    /// Chats whose messages are archived, initialised from ALLOWED_CHAT_IDS and changed
+    /// with /allow and /deny. Every chat when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_chat_ids: Option<BTreeSet<i64>>,
+    /// Chats not archived because the bot was removed from them, see
+    /// MEMBERSHIP_PAUSE_REMOVED.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub paused_chat_ids: BTreeSet<i64>,
+}
+
+impl RuntimeConfig {
+    pub fn downloads(&self, media_type: MediaType) -> bool {
+        self.download_types
+            .as_ref()
+            .is_none_or(|types| types.contains(&media_type))
+    }
+
+    pub fn chat_allowed(&self, chat_id: i64) -> bool {
+        !self.paused_chat_ids.contains(&chat_id)
+            && self
+                .allowed_chat_ids
+                .as_ref()
+                .is_none_or(|ids| ids.contains(&chat_id))
+    }
+
+    /// Root folder for downloads from `chat_id`.
+    pub fn download_dir(&self, chat_id: i64) -> PathBuf {
+        self.chat_download_dirs
+            .as_ref()
+            .and_then(|dirs| dirs.get(&chat_id))
+            .cloned()
+            .unwrap_or_else(|| DOWNLOAD_DIR.clone())
+    }
+
+    /// Records the bot joining or leaving `chat_id`. Leaving pauses the chat when
+    /// `pause_removed` is set, joining again resumes it. Returns whether the paused
+    /// chats changed.
+    pub fn set_member(&mut self, chat_id: i64, present: bool, pause_removed: bool) -> bool {
+        if present {
+            self.paused_chat_ids.remove(&chat_id)
+        } else {
+            pause_removed && self.paused_chat_ids.insert(chat_id)
+        }
+    }
+
+    /// Moves the per-chat settings of a group that became a supergroup to its new id.
+    /// Returns whether anything moved.
+    pub fn migrate(&mut self, from: i64, to: i64) -> bool {
+        let mut moved = false;
+        if let Some(dirs) = self.chat_download_dirs.as_mut()
+            && let Some(dir) = dirs.remove(&from)
+        {
+            dirs.insert(to, dir);
+            moved = true;
+        }
+        if let Some(ids) = self.allowed_chat_ids.as_mut()
+            && ids.remove(&from)
+        {
+            ids.insert(to);
+            moved = true;
+        }
+        if self.paused_chat_ids.remove(&from) {
+            self.paused_chat_ids.insert(to);
+            moved = true;
+        }
+        moved
+    }
+
+    /// DOWNLOAD_DIR and every per-chat folder, leaving out folders inside another one so
+    /// nothing is visited twice.
+    pub fn download_roots(&self) -> Vec<PathBuf> {
+        let dirs = std::iter::once(&*DOWNLOAD_DIR)
+            .chain(self.chat_download_dirs.iter().flatten().map(|(_, dir)| dir));
+        let dirs: BTreeSet<&PathBuf> = dirs.collect();
+        dirs.iter()
+            .filter(|dir| {
+                !dirs
+                    .iter()
+                    .any(|other| other != *dir && dir.starts_with(other))
+            })
+            .map(|dir| dir.to_path_buf())
+            .collect()
+    }
+
+    /// The settings to write to CONFIG_FILE: those that differ from the environment,
+    /// i.e. were changed through commands. The rest keep following the environment.
+    fn persisted(&self) -> RuntimeConfig {
+        let from_env = &*FROM_ENV;
+        RuntimeConfig {
+            download_types: changed(&self.download_types, &from_env.download_types),
+            chat_download_dirs: changed(&self.chat_download_dirs, &from_env.chat_download_dirs),
+            allowed_chat_ids: changed(&self.allowed_chat_ids, &from_env.allowed_chat_ids),
+            paused_chat_ids: self.paused_chat_ids.clone(),
+        }
+    }
+}
+
+fn changed<T: Clone + PartialEq>(value: &Option<T>, from_env: &Option<T>) -> Option<T> {
+    value.clone().filter(|_| value != from_env)
+}
+
+/// Drops the folders that aren't absolute, which would depend on the working directory.
+fn absolute_only(dirs: &mut BTreeMap<i64, PathBuf>) {
+    dirs.retain(|chat_id, dir| {
+        if !dir.is_absolute() {
+            log::warn!(
+                "Ignore download dir of chat {}, not absolute: {:?}",
+                chat_id,
+                dir
+            );
+        }
+        dir.is_absolute()
+    });
+}
+
+/// Parses DOWNLOAD_TYPES, a comma separated list like `photo,video`. All types when unset.
+fn download_types_from_env() -> BTreeSet<MediaType> {
+    match env::var("DOWNLOAD_TYPES") {
+        Ok(types) => types
+            .split(',')
+            .filter(|t| !t.trim().is_empty())
+            .filter_map(|t| {
+                t.parse()
+                    .inspect_err(|e| log::warn!("Ignore DOWNLOAD_TYPES entry: {:?}", e))
+                    .ok()
+            })
+            .collect(),
+        Err(_) => MediaType::ALL.into_iter().collect(),
+    }
+}
+
+/// Parses ALLOWED_CHAT_IDS, comma separated chat ids. None when unset.
+fn allowed_chat_ids_from_env() -> Option<BTreeSet<i64>> {
+    let ids = env::var("ALLOWED_CHAT_IDS").ok()?;
+    Some(
+        ids.split(',')
+            .filter(|id| !id.trim().is_empty())
+            .filter_map(|id| {
+                id.trim()
+                    .parse()
+                    .inspect_err(|e| log::warn!("Ignore ALLOWED_CHAT_IDS entry {:?}: {:?}", id, e))
+                    .ok()
+            })
+            .collect(),
+    )
+}
+
+/// Parses CHAT_DOWNLOAD_DIRS, a comma separated list like `-1001234=/mnt/archive`.
+fn chat_download_dirs_from_env() -> BTreeMap<i64, PathBuf> {
+    let mut dirs = env::var("CHAT_DOWNLOAD_DIRS")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| {
+            let parsed = entry
+                .split_once('=')
+                .and_then(|(chat_id, dir)| Some((chat_id.trim().parse().ok()?, dir.trim().into())));
+            if parsed.is_none() {
+                log::warn!("Ignore CHAT_DOWNLOAD_DIRS entry: {:?}", entry);
+            }
+            parsed
+        })
+        .collect();
+    absolute_only(&mut dirs);
+    dirs
+}
+
+pub async fn save(config: &RuntimeConfig) -> Result<()> {
+    let json = serde_json::to_string_pretty(&config.persisted())?;
+    tokio::fs::write(&*CONFIG_FILE, json)
+        .await
+        .with_context(|| format!("Save config error: {}", CONFIG_FILE.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_roots_skip_nested_folders() {
+        let config = RuntimeConfig {
+            chat_download_dirs: Some(BTreeMap::from([
+                (1, PathBuf::from("/mnt/archive")),
+                (2, PathBuf::from("/mnt/archive/chat2")),
+                (3, DOWNLOAD_DIR.join("chat3")),
+                (4, PathBuf::from("/mnt/archive")),
+            ])),
+            ..Default::default()
+        };
+        let roots: BTreeSet<_> = config.download_roots().into_iter().collect();
+        assert_eq!(
+            roots,
+            BTreeSet::from([DOWNLOAD_DIR.clone(), PathBuf::from("/mnt/archive")])
+        );
+    }
+
+    #[test]
+    fn removal_pauses_until_added_again() {
+        let mut config = RuntimeConfig::default();
+        assert!(!config.set_member(-1001, false, false));
+        assert!(config.chat_allowed(-1001));
+
+        assert!(config.set_member(-1001, false, true));
+        assert!(!config.chat_allowed(-1001));
+        assert!(!config.set_member(-1001, false, true));
+
+        assert!(config.set_member(-1001, true, true));
+        assert!(config.chat_allowed(-1001));
+        assert!(!config.set_member(-1001, true, true));
+    }
+
+    #[test]
+    fn migration_moves_per_chat_settings() {
+        let mut config = RuntimeConfig {
+            chat_download_dirs: Some(BTreeMap::from([(-1, PathBuf::from("/mnt/group"))])),
+            allowed_chat_ids: Some(BTreeSet::from([-1, -2])),
+            paused_chat_ids: BTreeSet::from([-1]),
+            ..Default::default()
+        };
+        assert!(config.migrate(-1, -1001));
+        assert_eq!(config.download_dir(-1001), PathBuf::from("/mnt/group"));
+        assert_eq!(config.allowed_chat_ids, Some(BTreeSet::from([-1001, -2])));
+        assert_eq!(config.paused_chat_ids, BTreeSet::from([-1001]));
+        assert!(!config.migrate(-1, -1001));
+    }
+
+    #[test]
+    fn only_changed_settings_are_persisted() {
+        let mut config = FROM_ENV.clone();
+        let persisted = config.persisted();
+        assert!(persisted.download_types.is_none());
+        assert!(persisted.chat_download_dirs.is_none());
+        assert!(persisted.allowed_chat_ids.is_none());
+
+        config.allowed_chat_ids = Some(BTreeSet::from([-1001]));
+        config.paused_chat_ids.insert(-1002);
+        let persisted = config.persisted();
+        assert_eq!(persisted.allowed_chat_ids, Some(BTreeSet::from([-1001])));
+        assert_eq!(persisted.paused_chat_ids, BTreeSet::from([-1002]));
+        assert!(persisted.chat_download_dirs.is_none());
+    }
+}
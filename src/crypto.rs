@@ -0,0 +1,311 @@
+use std::{
+    env,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use aes_gcm::{
+    Aes256Gcm, Key, KeyInit,
+    aead::{
+        OsRng,
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+    },
+};
+use anyhow::{Context, Result, anyhow, bail};
+use once_cell::sync::Lazy;
+use teloxide::types::ChatId;
+
+/// Header of encrypted files, followed by the nonce prefix and the STREAM chunks.
+const MAGIC: &[u8] = b"EATLNKE1";
+const NONCE_PREFIX_LEN: usize = 7;
+/// Plaintext bytes per STREAM chunk, which bounds memory use for large files.
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+/// Where downloads are written and post-processed with ENCRYPTION_KEY set, so the
+/// download folders only ever receive ciphertext. Keep it outside of them. A folder in
+/// the system temp dir when unset.
+pub static STAGING_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    env::var("STAGING_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| env::temp_dir().join("eatlink_staging"))
+});
+
+pub fn enabled() -> bool {
+    env::var("ENCRYPTION_KEY").is_ok()
+}
+
+/// Parses ENCRYPTION_KEY, a 32-byte key written as 64 hex characters.
+pub fn key() -> Result<Option<Key<Aes256Gcm>>> {
+    let Ok(hex) = env::var("ENCRYPTION_KEY") else {
+        return Ok(None);
+    };
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        bail!("ENCRYPTION_KEY must be 64 hex characters");
+    }
+    let mut key = Key::<Aes256Gcm>::default();
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .context("ENCRYPTION_KEY must be 64 hex characters")?;
+    }
+    Ok(Some(key))
+}
+
+/// Folder the media of `chat_id` is downloaded to before [`encrypt_to`] moves it into
+/// the archive, `None` when files aren't encrypted and go there directly.
+pub fn staging_dir(chat_id: ChatId) -> Option<PathBuf> {
+    enabled().then(|| STAGING_DIR.join(chat_id.to_string()))
+}
+
+/// Removes what interrupted downloads left in [`STAGING_DIR`]. Run before any download
+/// starts, since everything in it belongs to one that is in progress.
+pub fn clear_staging() -> Result<()> {
+    match std::fs::remove_dir_all(&*STAGING_DIR) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Clear staging dir {} error", STAGING_DIR.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Writes the AES-256-GCM ciphertext of the plaintext file `src` to `dst`, creating its
+/// folder. Returns the details recorded in the sidecar.
+///
+/// The ciphertext goes to `<dst>.encrypting` first and is renamed once complete, so
+/// `dst` never holds a partial file; [`sweep_plaintext`] removes what an interrupted
+/// call leaves.
+pub async fn encrypt_to(src: PathBuf, dst: PathBuf) -> Result<serde_json::Value> {
+    tokio::task::spawn_blocking(move || {
+        let key = key()?.ok_or(anyhow!("ENCRYPTION_KEY is not set"))?;
+        crate::ensure_dir_exists(dst.parent().unwrap_or(Path::new(".")))?;
+        let encrypted = dst.with_extension("encrypting");
+        let result = encrypt_file(&key, &src, &encrypted).and_then(|nonce| {
+            std::fs::rename(&encrypted, &dst)
+                .map(|_| nonce)
+                .map_err(Into::into)
+        });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&encrypted);
+        }
+        let nonce = result.with_context(|| format!("Encrypt file error: {}", src.display()))?;
+        Ok(serde_json::json!({
+            "algorithm": "AES-256-GCM STREAM-BE32",
+            "nonce": nonce.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            "chunk_size": CHUNK_SIZE,
+        }))
+    })
+    .await?
+}
+
+fn encrypt_file(key: &Key<Aes256Gcm>, src: &Path, dst: &Path) -> Result<[u8; NONCE_PREFIX_LEN]> {
+    let mut nonce = [0u8; NONCE_PREFIX_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let mut encryptor = EncryptorBE32::from_aead(Aes256Gcm::new(key), nonce.as_ref().into());
+
+    let mut reader = BufReader::new(File::open(src)?);
+    let mut writer = BufWriter::new(File::create(dst)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&nonce)?;
+    let mut chunk = read_chunk(&mut reader, CHUNK_SIZE)?;
+    loop {
+        let next = read_chunk(&mut reader, CHUNK_SIZE)?;
+        if next.is_empty() {
+            let sealed = encryptor
+                .encrypt_last(chunk.as_slice())
+                .map_err(|_| anyhow!("Encrypt last chunk error"))?;
+            writer.write_all(&sealed)?;
+            break;
+        }
+        let sealed = encryptor
+            .encrypt_next(chunk.as_slice())
+            .map_err(|_| anyhow!("Encrypt chunk error"))?;
+        writer.write_all(&sealed)?;
+        chunk = next;
+    }
+    writer.into_inner()?.sync_all()?;
+    Ok(nonce)
+}
+
+fn decrypt_file(key: &Key<Aes256Gcm>, src: &Path, dst: &Path) -> Result<()> {
+    let mut reader = BufReader::new(File::open(src)?);
+    let mut header = [0u8; MAGIC.len() + NONCE_PREFIX_LEN];
+    reader
+        .read_exact(&mut header)
+        .context("File is too short to be encrypted")?;
+    if &header[..MAGIC.len()] != MAGIC {
+        bail!("{} is not an encrypted archive file", src.display());
+    }
+    let nonce = &header[MAGIC.len()..];
+    let mut decryptor = DecryptorBE32::from_aead(Aes256Gcm::new(key), nonce.into());
+
+    let mut writer = BufWriter::new(File::create(dst)?);
+    let mut chunk = read_chunk(&mut reader, CHUNK_SIZE + TAG_SIZE)?;
+    loop {
+        let next = read_chunk(&mut reader, CHUNK_SIZE + TAG_SIZE)?;
+        if next.is_empty() {
+            let plain = decryptor
+                .decrypt_last(chunk.as_slice())
+                .map_err(|_| anyhow!("Decrypt last chunk error, wrong key or corrupted file"))?;
+            writer.write_all(&plain)?;
+            break;
+        }
+        let plain = decryptor
+            .decrypt_next(chunk.as_slice())
+            .map_err(|_| anyhow!("Decrypt chunk error, wrong key or corrupted file"))?;
+        writer.write_all(&plain)?;
+        chunk = next;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Folder under each download root that [`sweep_plaintext`] moves suspect files to.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// Cleans up the day folders under `roots` at startup. Half-written `.encrypting`
+/// files are removed. Downloads are staged in [`STAGING_DIR`], so plaintext media
+/// without a sidecar was never written by this version: it may be a download an older
+/// one didn't encrypt, but also a file someone put there, so it is moved to the same
+/// path under [`QUARANTINE_DIR`] for the operator to check. Plaintext files archived
+/// before ENCRYPTION_KEY was set have a sidecar, or are the original kept next to one,
+/// and stay. Returns how many files were removed and quarantined.
+pub fn sweep_plaintext(roots: &[PathBuf]) -> Result<(usize, usize)> {
+    let mut swept = (0, 0);
+    for root in roots.iter().filter(|root| root.is_dir()) {
+        // Only day folders, so files like CONFIG_FILE or logs next to them are never
+        // touched.
+        for entry in std::fs::read_dir(root)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if path.is_dir() && chrono::NaiveDate::parse_from_str(&name, "%Y-%m-%d").is_ok() {
+                let (removed, quarantined) = sweep_dir(root, &path)?;
+                swept.0 += removed;
+                swept.1 += quarantined;
+            }
+        }
+    }
+    Ok(swept)
+}
+
+fn sweep_dir(root: &Path, dir: &Path) -> Result<(usize, usize)> {
+    let (mut leftovers, mut plaintext, mut swept) = (Vec::new(), Vec::new(), (0, 0));
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if path.file_name().is_some_and(|name| name != "thumbnails") {
+                let (removed, quarantined) = sweep_dir(root, &path)?;
+                swept.0 += removed;
+                swept.1 += quarantined;
+            }
+            continue;
+        }
+        if path.extension().is_some_and(|e| e == "encrypting") {
+            leftovers.push(path);
+        } else if !crate::is_sidecar(&path)
+            && !crate::sidecar_paths(&path).iter().any(|p| p.exists())
+            && !is_encrypted(&path)?
+        {
+            plaintext.push(path);
+        }
+    }
+    if !plaintext.is_empty() {
+        // Originals kept by post-download steps have no sidecar of their own.
+        let mut originals = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if let Some(original) = crate::read_sidecar(&path)
+                .as_ref()
+                .and_then(|sidecar| sidecar["archive"]["original"].as_str())
+            {
+                originals.push(dir.join(original));
+            }
+        }
+        plaintext.retain(|path| !originals.contains(path));
+    }
+    for path in leftovers {
+        std::fs::remove_file(&path).with_context(|| format!("Remove {} error", path.display()))?;
+        log::warn!("Removed {:?} left by an interrupted encryption", path);
+        swept.0 += 1;
+    }
+    for path in plaintext {
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let target = root.join(QUARANTINE_DIR).join(relative);
+        crate::ensure_dir_exists(target.parent().unwrap_or(root))?;
+        std::fs::rename(&path, &target)
+            .with_context(|| format!("Quarantine {} error", path.display()))?;
+        log::warn!(
+            "Moved unencrypted {:?} without a sidecar to {:?}",
+            path,
+            target
+        );
+        swept.1 += 1;
+    }
+    Ok(swept)
+}
+
+fn is_encrypted(path: &Path) -> std::io::Result<bool> {
+    let mut header = Vec::with_capacity(MAGIC.len());
+    File::open(path)?
+        .take(MAGIC.len() as u64)
+        .read_to_end(&mut header)?;
+    Ok(header == MAGIC)
+}
+
+/// Reads up to `size` bytes, stopping early only at end of file.
+fn read_chunk(reader: &mut impl Read, size: usize) -> std::io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(size);
+    reader.take(size as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// `eatlink_bot decrypt <input> <output>`: decrypts an archived file with ENCRYPTION_KEY.
+pub fn run_decrypt(args: &[String]) -> Result<()> {
+    let [input, output] = args else {
+        bail!("Usage: eatlink_bot decrypt <input> <output>");
+    };
+    let key = key()?.ok_or(anyhow!("ENCRYPTION_KEY is not set"))?;
+    decrypt_file(&key, Path::new(input), Path::new(output))
+        .with_context(|| format!("Decrypt {} error", input))?;
+    log::info!("Decrypted {} to {}", input, output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_removes_partial_ciphertext_and_quarantines_plaintext() {
+        let root = crate::testing::TempDir::new("sweep");
+        let day = root.join("2024-01-01");
+        std::fs::create_dir_all(&day).unwrap();
+        let write = |path: PathBuf, data: &[u8]| {
+            std::fs::write(&path, data).unwrap();
+            path
+        };
+        let leftover = write(day.join("photo_a_1.jpg"), b"\xff\xd8\xff");
+        let partial = write(day.join("photo_b_2.encrypting"), b"EATL");
+        let encrypted = write(day.join("photo_c_3.jpg"), &[MAGIC, b"sealed"].concat());
+        let archived = write(day.join("video_d_4.mp4"), b"plain");
+        write(
+            day.join("video_d_4.mp4.json"),
+            br#"{"archive":{"original":"video_d_4.original.mp4"}}"#,
+        );
+        let original = write(day.join("video_d_4.original.mp4"), b"plain");
+        let outside = write(root.join("notes.txt"), b"plain");
+
+        let swept = sweep_plaintext(&[root.to_path_buf()]).unwrap();
+        let exists = [
+            &leftover, &partial, &encrypted, &archived, &original, &outside,
+        ]
+        .map(|path| path.exists());
+
+        assert_eq!(swept, (1, 1));
+        assert_eq!(exists, [false, false, true, true, true, true]);
+        let quarantined = root.join(QUARANTINE_DIR).join("2024-01-01/photo_a_1.jpg");
+        assert_eq!(std::fs::read(quarantined).unwrap(), b"\xff\xd8\xff");
+    }
+}
@@ -0,0 +1,133 @@
+use std::{path::Path, sync::atomic::Ordering, time::Duration};
+
+use chrono::{DateTime, Local};
+use once_cell::sync::Lazy;
+use teloxide::prelude::*;
+use tokio::sync::Mutex;
+
+use crate::{
+    DOWNLOAD_DIR, INFLIGHT, MAX_INFLIGHT, PAUSED, commands::human_size, config::RUNTIME_CONFIG,
+};
+
+/// Free space below which the disk check warns.
+const LOW_DISK_BYTES: u64 = 1024 * 1024 * 1024;
+const TELEGRAM_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// When a download failed, and why.
+type DownloadFailure = (DateTime<Local>, String);
+
+/// Latest download error, reported by /diag.
+pub static LAST_ERROR: Lazy<Mutex<Option<DownloadFailure>>> = Lazy::new(|| Mutex::new(None));
+
+pub struct DiskFree {
+    pub bytes: u64,
+    /// `None` on filesystems that don't report inodes, like btrfs.
+    pub inodes: Option<u64>,
+}
+
+/// Space and inodes available to the bot on the filesystem holding `path`.
+#[cfg(unix)]
+pub fn disk_free(path: &Path) -> std::io::Result<DiskFree> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(DiskFree {
+        bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        inodes: (stat.f_files > 0).then_some(stat.f_favail as u64),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn disk_free(_path: &Path) -> std::io::Result<DiskFree> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "free space is only checked on Unix",
+    ))
+}
+
+/// One OK/WARN/FAIL line per subsystem, for /diag.
+pub async fn report(bot: &Bot) -> String {
+    let telegram = match tokio::time::timeout(TELEGRAM_CHECK_TIMEOUT, bot.get_me()).await {
+        Ok(Ok(me)) => format!("OK   Telegram: @{}", me.username()),
+        Ok(Err(e)) => format!("FAIL Telegram: {}", e),
+        Err(_) => String::from("FAIL Telegram: 请求超时"),
+    };
+
+    let mut storage = Vec::new();
+    for root in RUNTIME_CONFIG.read().await.download_roots() {
+        storage.push(storage_line(&root).await);
+        storage.push(disk_line(&root));
+    }
+    let inflight = INFLIGHT.load(Ordering::SeqCst);
+    let queue = if PAUSED.load(Ordering::SeqCst) {
+        format!("WARN 下载: 已暂停，处理中 {}", inflight)
+    } else if MAX_INFLIGHT.is_some_and(|max| inflight >= max) {
+        format!("WARN 下载: 处理中 {}，已达 MAX_INFLIGHT", inflight)
+    } else {
+        format!("OK   下载: 处理中 {}", inflight)
+    };
+
+    let last_error = match LAST_ERROR.lock().await.as_ref() {
+        Some((at, error)) => format!(
+            "WARN 最近错误: {} {}",
+            at.format("%Y-%m-%d %H:%M:%S"),
+            error
+        ),
+        None => String::from("OK   最近错误: 无"),
+    };
+
+    std::iter::once(telegram)
+        .chain(storage)
+        .chain([queue, last_error])
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+async fn storage_line(root: &Path) -> String {
+    let probe = root.join(".eatlink_diag");
+    match tokio::fs::write(&probe, b"").await {
+        Ok(()) => {
+            let _ = tokio::fs::remove_file(&probe).await;
+            format!("OK   存储: {} 可写", root.display())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && root != DOWNLOAD_DIR.as_path() => {
+            format!("OK   存储: {} 尚未创建", root.display())
+        }
+        Err(e) => format!("FAIL 存储: {} 不可写: {}", root.display(), e),
+    }
+}
+
+fn disk_line(root: &Path) -> String {
+    // A per-chat folder that doesn't exist yet will be created on its filesystem.
+    let existing = root.ancestors().find(|dir| dir.exists()).unwrap_or(root);
+    match disk_free(existing) {
+        Ok(free) if free.bytes == 0 || free.inodes == Some(0) => {
+            format!(
+                "FAIL 磁盘: {} 可用 {}，inode {}",
+                root.display(),
+                human_size(free.bytes),
+                free.inodes.unwrap_or_default()
+            )
+        }
+        Ok(free) if free.bytes < LOW_DISK_BYTES => {
+            format!(
+                "WARN 磁盘: {} 仅剩 {}",
+                root.display(),
+                human_size(free.bytes)
+            )
+        }
+        Ok(free) => format!(
+            "OK   磁盘: {} 可用 {}",
+            root.display(),
+            human_size(free.bytes)
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+            format!("WARN 磁盘: {} 无法检查: {}", root.display(), e)
+        }
+        Err(e) => format!("FAIL 磁盘: {}: {}", root.display(), e),
+    }
+}
@@ -0,0 +1,140 @@
+use std::{collections::BTreeMap, env, path::PathBuf, time::Duration};
+
+use chrono::{Local, Timelike, Utc};
+use once_cell::sync::Lazy;
+use teloxide::types::ChatId;
+use tokio::sync::Mutex;
+
+use crate::TIMEZONE;
+
+/// JSON file the per-chat, per-hour download counts are kept in, like
+/// `{"<chat_id>": [<24 counts>]}`. Nothing is counted when unset.
+pub static HEATMAP_FILE: Lazy<Option<PathBuf>> =
+    Lazy::new(|| env::var("HEATMAP_FILE").ok().map(PathBuf::from));
+/// How often new counts are written to HEATMAP_FILE.
+pub static HEATMAP_FLUSH_SECS: Lazy<u64> = Lazy::new(|| {
+    env::var("HEATMAP_FLUSH_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(300)
+});
+
+/// Downloads per hour of the day in TIMEZONE, so each chat takes 24 counters however
+/// long the bot runs.
+type Counts = BTreeMap<i64, [u64; 24]>;
+
+struct Heatmap {
+    counts: Counts,
+    /// Whether `counts` changed since the last flush.
+    dirty: bool,
+}
+
+/// Counts so far, starting from HEATMAP_FILE so they survive restarts.
+static HEATMAP: Lazy<Mutex<Heatmap>> = Lazy::new(|| {
+    let counts = HEATMAP_FILE
+        .as_ref()
+        .and_then(|path| std::fs::read(path).ok())
+        .and_then(|json| {
+            serde_json::from_slice(&json)
+                .inspect_err(|e| log::warn!("Invalid HEATMAP_FILE, start over: {:?}", e))
+                .ok()
+        })
+        .unwrap_or_default();
+    Mutex::new(Heatmap {
+        counts,
+        dirty: false,
+    })
+});
+
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Effective heatmap settings for /config.
+pub fn settings() -> Vec<(&'static str, String)> {
+    vec![
+        ("HEATMAP_FILE", format!("{:?}", *HEATMAP_FILE)),
+        ("HEATMAP_FLUSH_SECS", HEATMAP_FLUSH_SECS.to_string()),
+    ]
+}
+
+pub fn enabled() -> bool {
+    HEATMAP_FILE.is_some()
+}
+
+/// Counts a download from `chat_id` in the current hour.
+pub async fn record(chat_id: ChatId) {
+    if !enabled() {
+        return;
+    }
+    let hour = match *TIMEZONE {
+        Some(timezone) => Utc::now().with_timezone(&timezone).hour(),
+        None => Local::now().hour(),
+    };
+    let mut heatmap = HEATMAP.lock().await;
+    heatmap.counts.entry(chat_id.0).or_default()[hour as usize] += 1;
+    heatmap.dirty = true;
+}
+
+/// Writes new counts to HEATMAP_FILE every HEATMAP_FLUSH_SECS.
+pub async fn flush_loop() {
+    loop {
+        tokio::time::sleep(Duration::from_secs(*HEATMAP_FLUSH_SECS)).await;
+        flush().await;
+    }
+}
+
+/// Writes the counts to HEATMAP_FILE if they changed.
+pub async fn flush() {
+    let Some(path) = HEATMAP_FILE.as_ref() else {
+        return;
+    };
+    let mut heatmap = HEATMAP.lock().await;
+    if !heatmap.dirty {
+        return;
+    }
+    let written = match serde_json::to_vec(&heatmap.counts) {
+        Ok(json) => tokio::fs::write(path, json).await,
+        Err(e) => Err(e.into()),
+    };
+    match written {
+        Ok(()) => heatmap.dirty = false,
+        Err(e) => log::warn!("Write HEATMAP_FILE {:?} error: {:?}", path, e),
+    }
+}
+
+/// Reply to /heatmap: one bar per hour for each chat. `reset` clears the counts.
+pub async fn reply(arg: &str) -> String {
+    if !enabled() {
+        return String::from("未启用 HEATMAP_FILE");
+    }
+    if arg == "reset" {
+        let mut heatmap = HEATMAP.lock().await;
+        heatmap.counts.clear();
+        heatmap.dirty = true;
+        drop(heatmap);
+        flush().await;
+        return String::from("已清空活跃度统计");
+    }
+    let heatmap = HEATMAP.lock().await;
+    if heatmap.counts.is_empty() {
+        return String::from("暂无活跃度统计");
+    }
+    let mut lines = vec![String::from("每小时下载数（0-23 时）:")];
+    for (chat_id, hours) in &heatmap.counts {
+        let total: u64 = hours.iter().sum();
+        let max = hours.iter().copied().max().unwrap_or_default().max(1);
+        let peak = hours
+            .iter()
+            .position(|count| *count == max)
+            .unwrap_or_default();
+        let bars: String = hours
+            .iter()
+            .map(|count| BARS[(*count * (BARS.len() as u64 - 1)).div_ceil(max) as usize])
+            .collect();
+        lines.push(format!(
+            "{}: 共 {}，高峰 {} 时\n{}",
+            chat_id, total, peak, bars
+        ));
+    }
+    lines.join("\n")
+}
@@ -0,0 +1,256 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, NaiveDate};
+use serde_json::{Value, json};
+use teloxide::types::UserId;
+
+use crate::{
+    Placement, TIMEZONE, archive_dir, config::RUNTIME_CONFIG, crypto, date_in, ensure_dir_exists,
+    safe_file_name, write_sidecar,
+};
+
+/// `eatlink_bot import-export <dir>`: copies the photos and videos of a Telegram
+/// Desktop JSON export (the folder holding `result.json`) into the archive.
+///
+/// Files land in the folder the bot would have saved them in on their message date, in
+/// TIMEZONE, with the sender folder of GROUP_BY_SENDER. Exports carry no topic ids, so
+/// GROUP_BY_TOPIC doesn't apply. Each gets a sidecar shaped like the ones the bot writes, plus
+/// `archive.imported_from`. Files already in the archive are skipped, so an interrupted
+/// import can simply be run again. With ENCRYPTION_KEY set, imported files are
+/// encrypted like downloaded ones.
+pub async fn run_import(args: &[String]) -> Result<()> {
+    let [dir] = args else {
+        bail!("Usage: eatlink_bot import-export <dir>");
+    };
+    let dir = Path::new(dir);
+    let export_file = dir.join("result.json");
+    let export: Value = serde_json::from_slice(
+        &std::fs::read(&export_file)
+            .with_context(|| format!("Read {} error", export_file.display()))?,
+    )
+    .with_context(|| format!("Parse {} error", export_file.display()))?;
+    let Some(peer_id) = export["id"].as_i64() else {
+        bail!(
+            "{} has no chat id, export a single chat",
+            export_file.display()
+        );
+    };
+    let Some((chat_id, chat_type)) = bot_api_chat(peer_id, export["type"].as_str()) else {
+        bail!(
+            "Unknown chat type {} in {}",
+            export["type"],
+            export_file.display()
+        );
+    };
+    let chat = json!({
+        "id": chat_id,
+        "title": export["name"],
+        "type": chat_type,
+    });
+    let root = RUNTIME_CONFIG.read().await.download_dir(chat_id);
+
+    let (mut imported, mut skipped) = (0, 0);
+    for message in export["messages"].as_array().into_iter().flatten() {
+        let Some((kind, source)) = media_of(message) else {
+            continue;
+        };
+        let source = dir.join(source);
+        if !source.is_file() {
+            log::warn!("Skip message {}, missing {:?}", message["id"], &source);
+            continue;
+        }
+        let Some(day) = message_day(message, *TIMEZONE) else {
+            log::warn!("Skip message {}, no date", message["id"]);
+            continue;
+        };
+
+        let folder = archive_dir(&root, day, &placement(message));
+        let path = folder.join(import_file_name(kind, message, &source));
+        if path.exists() {
+            skipped += 1;
+            continue;
+        }
+        let mut sidecar = sidecar(message, &chat, kind, &source);
+        if crypto::enabled() {
+            // Straight from the export, so no plaintext copy lands in the archive.
+            sidecar["archive"]["encryption"] =
+                crypto::encrypt_to(source.clone(), path.clone()).await?;
+        } else {
+            ensure_dir_exists(&folder)?;
+            std::fs::copy(&source, &path)
+                .with_context(|| format!("Copy {} error", source.display()))?;
+        }
+        write_sidecar(&path, &sidecar)?;
+        log::debug!("Imported {:?} to {:?}", &source, &path);
+        imported += 1;
+    }
+    log::info!(
+        "Imported {} files from {}, skipped {} already archived",
+        imported,
+        dir.display(),
+        skipped
+    );
+    Ok(())
+}
+
+/// Bot API id and type of an exported chat. Exports keep the bare peer id, which the
+/// Bot API prefixes with `-` for basic groups and `-100` for supergroups and channels.
+fn bot_api_chat(peer_id: i64, export_type: Option<&str>) -> Option<(i64, &'static str)> {
+    match export_type? {
+        "personal_chat" | "bot_chat" | "saved_messages" => Some((peer_id, "private")),
+        "private_group" => Some((-peer_id, "group")),
+        "private_supergroup" | "public_supergroup" => {
+            Some((-1_000_000_000_000 - peer_id, "supergroup"))
+        }
+        "private_channel" | "public_channel" => Some((-1_000_000_000_000 - peer_id, "channel")),
+        _ => None,
+    }
+}
+
+/// The media kind and export-relative path of a message's photo or video.
+fn media_of(message: &Value) -> Option<(&'static str, &str)> {
+    if let Some(photo) = message["photo"].as_str() {
+        return Some(("photo", photo));
+    }
+    match message["media_type"].as_str() {
+        Some("video_file") => Some(("video", message["file"].as_str()?)),
+        _ => None,
+    }
+}
+
+/// `<kind>_export_<message_id>.<ext>`. The export has no file unique ids, so this can't
+/// match the names of files the bot downloaded itself.
+fn import_file_name(kind: &str, message: &Value, source: &Path) -> String {
+    let extension = source
+        .extension()
+        .map(|e| e.to_string_lossy().into_owned())
+        .unwrap_or(String::from("bin"));
+    safe_file_name(&format!("{}_export_{}.{}", kind, message["id"], extension))
+}
+
+/// Day of the message in `timezone`, from its UTC timestamp. The export's `date` is in
+/// the local time of the machine that made it.
+fn message_day(message: &Value, timezone: Option<chrono_tz::Tz>) -> Option<NaiveDate> {
+    let timestamp = message["date_unixtime"].as_str()?.parse().ok()?;
+    Some(date_in(DateTime::from_timestamp(timestamp, 0)?, timezone))
+}
+
+/// The sending user of an export message, whose `from_id` is `user<id>`, or
+/// `channel<id>` for posts signed by a channel.
+fn sender_id(message: &Value) -> Option<u64> {
+    message["from_id"]
+        .as_str()
+        .and_then(|id| id.strip_prefix("user"))
+        .and_then(|id| id.parse().ok())
+}
+
+/// Exports have no usernames, so GROUP_BY_SENDER=username falls back to the user id
+/// folder, as for users without one.
+fn placement(message: &Value) -> Placement {
+    Placement {
+        topic_id: None,
+        forum: false,
+        sender: sender_id(message).map(|id| (UserId(id), None)),
+    }
+}
+
+/// Maps an export message to the Bot API message fields the archive reads.
+fn sidecar(message: &Value, chat: &Value, kind: &str, source: &Path) -> Value {
+    let mut media = json!({
+        "file_name": source.file_name().map(|n| n.to_string_lossy().into_owned()),
+        "mime_type": message["mime_type"],
+    });
+    for key in ["width", "height", "duration_seconds"] {
+        if let Some(value) = message.get(key) {
+            media[key] = value.clone();
+        }
+    }
+    let from_id = sender_id(message);
+    json!({
+        "message_id": message["id"],
+        "date": message["date_unixtime"].as_str().and_then(|d| d.parse::<i64>().ok()),
+        "chat": chat,
+        "from": from_id.map(|id| json!({ "id": id, "first_name": message["from"] })),
+        "caption": plain_text(&message["text"]),
+        kind: media,
+        "archive": { "imported_from": PathBuf::from(source) },
+    })
+}
+
+/// Export text is either a string or a list of strings and entity objects.
+fn plain_text(text: &Value) -> Option<String> {
+    let text = match text {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part.as_str().or(part["text"].as_str()))
+            .collect(),
+        _ => return None,
+    };
+    (!text.is_empty()).then_some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_ids_map_to_bot_api_ids() {
+        assert_eq!(
+            bot_api_chat(42, Some("personal_chat")),
+            Some((42, "private"))
+        );
+        assert_eq!(
+            bot_api_chat(42, Some("private_group")),
+            Some((-42, "group"))
+        );
+        assert_eq!(
+            bot_api_chat(1234567890, Some("public_supergroup")),
+            Some((-1001234567890, "supergroup"))
+        );
+        assert_eq!(
+            bot_api_chat(1234567890, Some("private_channel")),
+            Some((-1001234567890, "channel"))
+        );
+        assert_eq!(bot_api_chat(42, Some("something_new")), None);
+        assert_eq!(bot_api_chat(42, None), None);
+    }
+
+    #[test]
+    fn plain_text_joins_entities() {
+        assert_eq!(plain_text(&json!("hello")), Some(String::from("hello")));
+        assert_eq!(
+            plain_text(&json!(["see ", { "type": "link", "text": "example.com" }, "!"])),
+            Some(String::from("see example.com!"))
+        );
+        assert_eq!(plain_text(&json!("")), None);
+        assert_eq!(plain_text(&json!([])), None);
+        assert_eq!(plain_text(&Value::Null), None);
+    }
+
+    #[test]
+    fn imports_go_to_the_folder_of_their_day_and_sender() {
+        // 2023-11-14 22:13:20 UTC, already the next day in Shanghai.
+        let message = json!({
+            "date": "2023-11-14T23:13:20",
+            "date_unixtime": "1700000000",
+            "from_id": "user42",
+        });
+        let shanghai = Some(chrono_tz::Asia::Shanghai);
+        let day = message_day(&message, shanghai).unwrap();
+        assert_eq!(day, NaiveDate::from_ymd_opt(2023, 11, 15).unwrap());
+        assert_eq!(
+            message_day(&message, Some(chrono_tz::UTC)).unwrap(),
+            NaiveDate::from_ymd_opt(2023, 11, 14).unwrap()
+        );
+
+        let dir = PathBuf::from("2023-11-15");
+        let by_sender =
+            |message| crate::grouped_dir(dir.clone(), &placement(message), true, Some("username"));
+        assert_eq!(by_sender(&message), dir.join("42"));
+        let channel_post = json!({ "from_id": "channel7" });
+        assert_eq!(by_sender(&channel_post), dir.join("anonymous"));
+        assert_eq!(message_day(&channel_post, shanghai), None);
+    }
+}
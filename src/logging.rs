@@ -0,0 +1,51 @@
+use std::{
+    env,
+    io::{self, Write},
+    path::Path,
+};
+
+use pretty_env_logger::env_logger::Target;
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling,
+};
+
+/// Initialises logging from RUST_LOG. With LOG_FILE set, logs are also appended to that
+/// file, rotated daily into `<LOG_FILE>.<date>`.
+///
+/// File writes go through a background thread, so a slow disk never blocks download
+/// tasks. Keep the returned guard alive until exit so buffered lines are flushed.
+pub fn init() -> Option<WorkerGuard> {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let guard = env::var("LOG_FILE").ok().map(|log_file| {
+        let path = Path::new(&log_file);
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let prefix = path.file_name().unwrap_or(path.as_os_str());
+        let (file, guard) = tracing_appender::non_blocking(rolling::daily(dir, prefix));
+        builder.target(Target::Pipe(Box::new(Tee { file })));
+        guard
+    });
+    builder.init();
+    guard
+}
+
+/// Writes log lines to both stderr and the rolling log file.
+struct Tee {
+    file: NonBlocking,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        self.file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        self.file.flush()
+    }
+}
@@ -1,27 +1,57 @@
+mod commands;
+mod config;
+mod crypto;
+mod diag;
+mod heatmap;
+mod import;
+mod logging;
+mod membership;
+mod priority;
+mod retention;
+mod retraction;
+#[cfg(test)]
+mod testing;
+mod throttle;
+mod thumbnail;
+mod transcode;
+mod watermark;
+mod zipme;
+
 use std::{
+    collections::{HashMap, HashSet},
     env::{self},
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
-use chrono::Local;
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use commands::{Command, handle_command};
+use config::{MediaType, RUNTIME_CONFIG};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
 use once_cell::sync::Lazy;
 use teloxide::{
-    Bot, RequestError,
-    dispatching::dialogue::GetChatId,
+    ApiError, Bot, DownloadError as TeloxideDownloadError, RequestError,
     net::Download,
     prelude::*,
     sugar::request::RequestReplyExt,
-    types::{Message, MessageId},
+    types::{
+        Chat, ChatKind, ChatPublic, File as TgFile, FileMeta, MediaKind, Message, MessageId,
+        MessageKind, ParseMode, PublicChatKind, PublicChatSupergroup, ReactionType, ThreadId,
+    },
+    update_listeners::webhooks,
+    utils::{html, markdown, render::RenderMessageTextHelper},
 };
 use tokio::{
     fs::File,
-    io::{AsyncWriteExt, BufWriter},
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
     sync::{
-        Mutex,
-        mpsc::{Receiver, channel},
+        Mutex, Semaphore,
+        mpsc::{Receiver, Sender, channel},
     },
     time::sleep,
 };
@@ -30,16 +60,392 @@ const RECEIVE_TIMEOUT: u64 = 2;
 pub static DOWNLOAD_DIR: Lazy<PathBuf> = Lazy::new(|| {
     env::var("DOWNLOAD_DIR")
         .map(PathBuf::from)
-        .unwrap_or_else(|_| {
-            let default = Path::new("downloads").to_path_buf();
-            default
+        .unwrap_or_else(|_| Path::new("downloads").to_path_buf())
+});
+
+/// IANA timezone, like `Asia/Shanghai`, that decides which day folder a download goes
+/// to. The system timezone is used when unset, which is UTC in most containers.
+pub static TIMEZONE: Lazy<Option<chrono_tz::Tz>> = Lazy::new(|| {
+    let name = env::var("TIMEZONE").ok()?;
+    name.parse()
+        .inspect_err(|e| log::warn!("Unknown TIMEZONE {}, use local time: {:?}", name, e))
+        .ok()
+});
+
+/// Nest forum group downloads under a `topic_<id>` folder inside the day folder.
+pub static GROUP_BY_TOPIC: Lazy<bool> = Lazy::new(|| env_flag("GROUP_BY_TOPIC"));
+
+/// Nest downloads under a folder per sender when set to `id` (or `1`/`true`/`yes`) or
+/// `username`. Users without a username fall back to their id. Posts made on behalf of
+/// a chat go to `anonymous`.
+pub static GROUP_BY_SENDER: Lazy<Option<String>> = Lazy::new(|| {
+    let mode = env::var("GROUP_BY_SENDER").ok()?.to_lowercase();
+    match mode.as_str() {
+        "username" => Some(mode),
+        "id" | "1" | "true" | "yes" => Some(String::from("id")),
+        _ => None,
+    }
+});
+
+/// Parse mode for the statistics reply, `MarkdownV2` or `HTML`. Plain text when unset.
+pub static SUMMARY_PARSE_MODE: Lazy<Option<ParseMode>> = Lazy::new(|| {
+    let mode = env::var("SUMMARY_PARSE_MODE").ok()?;
+    match mode.to_lowercase().as_str() {
+        "markdownv2" => Some(ParseMode::MarkdownV2),
+        "html" => Some(ParseMode::Html),
+        _ => {
+            log::warn!(
+                "Unknown SUMMARY_PARSE_MODE {}, fall back to plain text",
+                mode
+            );
+            None
+        }
+    }
+});
+
+/// `compact` sums up the archived items of an album in one statistic line, like
+/// `相册: 10 项 (8 图 2 视频)`, or `相册: 9 成功 1 失败 (9 图)` when some failed.
+/// `detailed`, the default, lists each item.
+pub static ALBUM_REPLY_COMPACT: Lazy<bool> = Lazy::new(|| {
+    let mode = env::var("ALBUM_REPLY_MODE").unwrap_or_default();
+    match mode.to_lowercase().as_str() {
+        "compact" => true,
+        "" | "detailed" => false,
+        _ => {
+            log::warn!("Unknown ALBUM_REPLY_MODE {}, fall back to detailed", mode);
+            false
+        }
+    }
+});
+
+/// Reply to each message whose download failed, besides listing it in the statistics.
+pub static REPLY_ON_FAILURE: Lazy<bool> = Lazy::new(|| env_flag("REPLY_ON_FAILURE"));
+
+/// Chat that receives notifications meant for the operator.
+pub static ADMIN_CHAT_ID: Lazy<Option<ChatId>> = Lazy::new(|| {
+    env::var("ADMIN_CHAT_ID")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(ChatId)
+});
+
+/// How updates are received: `polling`, the default, or `webhook`, which has Telegram
+/// push them to WEBHOOK_URL.
+pub static UPDATE_MODE: Lazy<String> = Lazy::new(|| {
+    env::var("UPDATE_MODE")
+        .map(|mode| mode.trim().to_lowercase())
+        .unwrap_or(String::from("polling"))
+});
+/// Local address the webhook server listens on, like `0.0.0.0:8443`.
+pub static WEBHOOK_LISTEN: Lazy<Option<String>> = Lazy::new(|| env::var("WEBHOOK_LISTEN").ok());
+/// Public HTTPS URL Telegram sends updates to. Its path is served by the webhook server.
+pub static WEBHOOK_URL: Lazy<Option<String>> = Lazy::new(|| env::var("WEBHOOK_URL").ok());
+
+/// Tell ADMIN_CHAT_ID when the bot has started, with its version and settings.
+pub static STARTUP_NOTIFY: Lazy<bool> = Lazy::new(|| env_flag("STARTUP_NOTIFY"));
+
+/// ADMIN_CHAT_ID when SUMMARY_TARGET=admin, in which case the statistics of every chat
+/// are sent there instead of as a reply in the source chat.
+static SUMMARY_ADMIN_CHAT: Lazy<Option<ChatId>> =
+    Lazy::new(|| match env::var("SUMMARY_TARGET").as_deref() {
+        Ok("admin") if ADMIN_CHAT_ID.is_none() => {
+            log::warn!("SUMMARY_TARGET=admin needs ADMIN_CHAT_ID, reply in chat instead");
+            None
+        }
+        Ok("admin") => *ADMIN_CHAT_ID,
+        _ => None,
+    });
+
+/// fsync every downloaded file, its sidecar and their folder before reporting success,
+/// so a power loss can't leave a truncated download behind. Each fsync waits for the
+/// disk, which noticeably slows bursts of small files on spinning disks.
+pub static FSYNC_WRITES: Lazy<bool> = Lazy::new(|| env_flag("FSYNC_WRITES"));
+
+/// Save the caption rendered as HTML in the sidecar, which keeps the URLs of text links
+/// that the plain `caption` drops.
+pub static PRESERVE_CAPTION_LINKS: Lazy<bool> = Lazy::new(|| env_flag("PRESERVE_CAPTION_LINKS"));
+
+/// Save the t.me link of each message in its sidecar and add it to the statistics.
+/// Public chats get `t.me/<username>/<id>`, other supergroups and channels the
+/// `t.me/c/...` form. Private chats and basic groups have no link.
+pub static EMBED_MESSAGE_LINK: Lazy<bool> = Lazy::new(|| env_flag("EMBED_MESSAGE_LINK"));
+
+/// Check downloaded files against their content and rename mislabeled ones, like a
+/// "video" that is really a GIF, to the extension the content shows.
+pub static VERIFY_MEDIA_TYPE: Lazy<bool> = Lazy::new(|| env_flag("VERIFY_MEDIA_TYPE"));
+
+/// Record which message a media message replied to, with the start of its text, under
+/// `archive.reply_to` in the sidecar.
+pub static SAVE_REPLY_CONTEXT: Lazy<bool> = Lazy::new(|| env_flag("SAVE_REPLY_CONTEXT"));
+
+/// Only archive media whose caption matches this regex. Checked at startup.
+pub static CAPTION_FILTER_REGEX: Lazy<Result<Option<regex::Regex>, regex::Error>> =
+    Lazy::new(|| {
+        env::var("CAPTION_FILTER_REGEX")
+            .ok()
+            .map(|pattern| regex::Regex::new(&pattern))
+            .transpose()
+    });
+/// With CAPTION_FILTER_REGEX set, also archive media that has no caption.
+pub static CAPTION_FILTER_INCLUDE_EMPTY: Lazy<bool> =
+    Lazy::new(|| env_flag("CAPTION_FILTER_INCLUDE_EMPTY"));
+
+/// Acknowledge each archived file with a reaction on its message instead of listing it
+/// in the statistics reply. Where reactions aren't allowed, the line is listed as usual.
+pub static REACT_INSTEAD_OF_REPLY: Lazy<bool> = Lazy::new(|| env_flag("REACT_INSTEAD_OF_REPLY"));
+/// Emoji used by REACT_INSTEAD_OF_REPLY. Telegram only accepts some emoji as reactions.
+pub static REACTION_EMOJI: Lazy<String> =
+    Lazy::new(|| env::var("REACTION_EMOJI").unwrap_or(String::from("👍")));
+
+/// Only archive media uploaded by the sender, skipping anything forwarded.
+pub static SKIP_FORWARDS: Lazy<bool> = Lazy::new(|| env_flag("SKIP_FORWARDS"));
+
+/// Most statistic lines listed in one reply. Larger batches end with a count of the rest.
+pub static MAX_ITEMS_PER_REPLY: Lazy<Option<usize>> = Lazy::new(|| {
+    env::var("MAX_ITEMS_PER_REPLY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+});
+
+/// Only archive media sent by these users, comma separated. Everyone when unset.
+pub static ALLOWED_USER_IDS: Lazy<Vec<UserId>> = Lazy::new(|| {
+    env::var("ALLOWED_USER_IDS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.trim().parse().ok())
+        .map(UserId)
+        .collect()
+});
+
+/// With ALLOWED_USER_IDS set, still archive posts made on behalf of a chat (channel
+/// posts, anonymous admins), which have no user to check.
+pub static ALLOW_CHAT_SENDERS: Lazy<bool> = Lazy::new(|| env_flag("ALLOW_CHAT_SENDERS"));
+
+/// Save service messages (members joining, pins, title changes...) as
+/// `service_<message_id>.json` in the day folder, for a complete chat log.
+pub static ARCHIVE_SERVICE_MESSAGES: Lazy<bool> =
+    Lazy::new(|| env_flag("ARCHIVE_SERVICE_MESSAGES"));
+
+/// Save the message JSON of attachments the bot doesn't download, like documents or
+/// stickers, as `<type>_<message_id>.json` in the day folder. They are only logged
+/// when unset. Attachment types Telegram added after teloxide are saved as
+/// `unknown_<message_id>.json`, with the message ids but not the attachment itself.
+pub static ARCHIVE_UNKNOWN_MEDIA: Lazy<bool> = Lazy::new(|| env_flag("ARCHIVE_UNKNOWN_MEDIA"));
+
+/// Archive media whose caption is a bot command, like a photo captioned `/something`.
+/// Such messages are skipped by default.
+pub static ARCHIVE_COMMAND_MEDIA: Lazy<bool> = Lazy::new(|| env_flag("ARCHIVE_COMMAND_MEDIA"));
+
+/// Longest flood-wait to sleep through before deferring a reply instead.
+pub static MAX_RETRY_AFTER_SECS: Lazy<u32> = Lazy::new(|| {
+    env::var("MAX_RETRY_AFTER_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30)
+});
+
+/// Skip downloads while the archive's filesystem has fewer free inodes than this. Not
+/// checked when unset, or on filesystems that don't report inodes.
+pub static MIN_FREE_INODES: Lazy<Option<u64>> = Lazy::new(|| {
+    env::var("MIN_FREE_INODES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+});
+
+/// Downloads whose size differs from the reported size are retried this many times.
+pub static TRUNCATED_RETRIES: Lazy<u32> = Lazy::new(|| {
+    env::var("TRUNCATED_RETRIES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2)
+});
+
+/// Retries of the get_file step, which resolves a file id to a download path.
+pub static GET_FILE_RETRY: Lazy<RetryPolicy> =
+    Lazy::new(|| RetryPolicy::from_env("GET_FILE_ATTEMPTS", "GET_FILE_BACKOFF_MS"));
+/// Retries of the download_file step, which fetches the file from that path.
+pub static DOWNLOAD_RETRY: Lazy<RetryPolicy> =
+    Lazy::new(|| RetryPolicy::from_env("DOWNLOAD_ATTEMPTS", "DOWNLOAD_BACKOFF_MS"));
+
+/// How often a network step is attempted in total, and the pause before its first
+/// retry, which doubles on every further retry.
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    fn from_env(attempts_key: &str, backoff_key: &str) -> Self {
+        let attempts = env::var(attempts_key)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|attempts| *attempts > 0)
+            .unwrap_or(3);
+        let backoff = env::var(backoff_key)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1000);
+        RetryPolicy {
+            attempts,
+            backoff: Duration::from_millis(backoff),
+        }
+    }
+
+    /// Pause after the failed `attempt`, counted from 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+}
+
+/// Most messages queued or downloading at once. Further messages are refused with a
+/// busy reply instead of piling up in memory. No limit when unset.
+pub static MAX_INFLIGHT: Lazy<Option<usize>> =
+    Lazy::new(|| env::var("MAX_INFLIGHT").ok().and_then(|v| v.parse().ok()));
+/// Set by /globalpause. Messages received meanwhile are skipped, not queued for later.
+pub static PAUSED: AtomicBool = AtomicBool::new(false);
+/// Messages handed to the consumer whose download hasn't finished yet.
+pub static INFLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// A message counted in INFLIGHT, from the handler until its download task finishes.
+struct Inflight(&'static AtomicUsize);
+
+impl Inflight {
+    /// Counts a message in `counter` unless `max` messages are already in flight, in
+    /// which case the count is returned instead.
+    fn admit(counter: &'static AtomicUsize, max: Option<usize>) -> Result<Inflight, usize> {
+        let inflight = counter.fetch_add(1, Ordering::SeqCst);
+        if max.is_some_and(|max| inflight >= max) {
+            counter.fetch_sub(1, Ordering::SeqCst);
+            return Err(inflight);
+        }
+        Ok(Inflight(counter))
+    }
+}
+
+impl Drop for Inflight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Quiet period after the last album item before the summary is sent. Albums can
+/// arrive slower than RECEIVE_TIMEOUT, and this keeps one album in one summary.
+pub static ALBUM_TIMEOUT_SECS: Lazy<u64> = Lazy::new(|| {
+    env::var("ALBUM_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(6)
+});
+
+/// Most recent file archived per chat, for /rename. Kept in memory only.
+pub static LAST_FILES: Lazy<Mutex<HashMap<ChatId, PathBuf>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Indent sidecar JSON for reading by hand. Set to `false` for compact sidecars, which
+/// are typically around a third smaller. Both forms are read back the same way.
+pub static METADATA_PRETTY: Lazy<bool> = Lazy::new(|| {
+    env::var("METADATA_PRETTY")
+        .map(|v| !matches!(v.to_lowercase().as_str(), "0" | "false" | "no"))
+        .unwrap_or(true)
+});
+
+/// Dotted JSON paths removed from sidecars before writing, comma separated, like
+/// `contact.phone_number,location`. Arrays on the way are searched element by element.
+/// `default` expands to DEFAULT_REDACT_FIELDS. Nothing is removed when unset.
+pub static REDACT_FIELDS: Lazy<Vec<String>> = Lazy::new(|| {
+    env::var("REDACT_FIELDS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .flat_map(|field| match field {
+            "default" => DEFAULT_REDACT_FIELDS
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+            field => vec![field.to_owned()],
         })
+        .collect()
+});
+
+/// Contact details and precise locations, in the message and the message it replies to.
+const DEFAULT_REDACT_FIELDS: &[&str] = &[
+    "contact.phone_number",
+    "contact.vcard",
+    "location",
+    "venue.location",
+    "reply_to_message.contact.phone_number",
+    "reply_to_message.contact.vcard",
+    "reply_to_message.location",
+    "reply_to_message.venue.location",
+];
+
+/// Most CPU-heavy post-download jobs (watermarking, transcoding, thumbnails) running at
+/// once. They run outside the async workers, on blocking threads or in ffmpeg, and
+/// this keeps them from taking every core while downloads continue. Defaults to half
+/// the available cores.
+pub static HOOK_CONCURRENCY: Lazy<usize> = Lazy::new(|| {
+    env::var("HOOK_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| (n.get() / 2).max(1)))
 });
+pub static HOOK_PERMITS: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(*HOOK_CONCURRENCY));
+
+/// Largest sidecar JSON, in bytes, before compression. Bigger ones, like messages with
+/// huge entity lists, are replaced by a minimal version. No limit when unset.
+pub static MAX_METADATA_BYTES: Lazy<Option<usize>> = Lazy::new(|| {
+    env::var("MAX_METADATA_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+});
+
+/// Gzip metadata sidecars to `<file>.json.gz` instead of writing plain `<file>.json`.
+pub static COMPRESS_METADATA: Lazy<bool> = Lazy::new(|| env_flag("COMPRESS_METADATA"));
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    pretty_env_logger::init();
+    let _log_guard = logging::init();
+    priority::apply();
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("decrypt") {
+        crypto::run_decrypt(&args[2..])?;
+        return Ok(());
+    }
+    if args.get(1).map(String::as_str) == Some("import-export") {
+        import::run_import(&args[2..]).await?;
+        return Ok(());
+    }
+    // Fail fast on a malformed key rather than at the first download.
+    crypto::key()?;
+    CAPTION_FILTER_REGEX
+        .as_ref()
+        .map_err(|e| format!("Invalid CAPTION_FILTER_REGEX: {}", e))?;
     ensure_dir_exists(&DOWNLOAD_DIR)?;
+    if crypto::enabled() {
+        // Before any download starts, so nothing in progress is mistaken for a leftover.
+        if let Err(e) = crypto::clear_staging() {
+            log::warn!("{:?}", e);
+        }
+        let roots = RUNTIME_CONFIG.read().await.download_roots();
+        let swept = tokio::task::spawn_blocking(move || crypto::sweep_plaintext(&roots)).await;
+        match swept.map_err(anyhow::Error::from).and_then(|r| r) {
+            Ok((_, quarantined)) if quarantined > 0 => log::warn!(
+                "Quarantined {} unencrypted files, check the {} folders",
+                quarantined,
+                crypto::QUARANTINE_DIR
+            ),
+            Ok(_) => {}
+            Err(e) => log::warn!("Sweep unencrypted leftovers error: {:?}", e),
+        }
+    }
+    let webhook = match UPDATE_MODE.as_str() {
+        "polling" => None,
+        "webhook" => Some(webhook_options()?),
+        mode => return Err(format!("Unknown UPDATE_MODE {}", mode).into()),
+    };
     log::info!("Starting bot...");
 
     let (tx, rx) = channel(20);
@@ -47,69 +453,444 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let tx = Arc::new(tx);
 
     let consumer = tokio::spawn(consumer_loop(bot.clone(), rx));
-    teloxide::repl(bot.clone(), {
-        let tx = tx.clone();
-        move |_: Arc<Bot>, msg: Message| {
-            let tx = tx.clone();
-            async move {
+    if heatmap::enabled() {
+        tokio::spawn(heatmap::flush_loop());
+    }
+    if *STARTUP_NOTIFY && let Some(admin_chat_id) = *ADMIN_CHAT_ID {
+        tokio::spawn(notify_startup(bot.clone(), admin_chat_id));
+    }
+    let messages = Update::filter_message()
+        .branch(
+            dptree::entry()
+                .filter_command::<Command>()
+                .endpoint(handle_command),
+        )
+        .branch(dptree::endpoint(
+            |bot: Arc<Bot>, msg: Message, tx: Arc<Sender<(Message, Inflight)>>| async move {
+                if let Some(from) = msg.migrate_from_chat_id() {
+                    migrate_chat(*from, msg.chat.id).await;
+                    return Ok(());
+                }
+                if let Some(to) = msg.migrate_to_chat_id() {
+                    migrate_chat(msg.chat.id, *to).await;
+                    return Ok(());
+                }
+                if !sender_allowed(&msg) {
+                    log::info!(
+                        "Skip message {} from sender {:?} not in ALLOWED_USER_IDS",
+                        msg.id,
+                        msg.from.as_ref().map(|u| u.id)
+                    );
+                    return Ok(());
+                }
+                if !RUNTIME_CONFIG.read().await.chat_allowed(msg.chat.id.0) {
+                    log::info!(
+                        "Skip message {} from chat {} not in the allowlist",
+                        msg.id,
+                        msg.chat.id
+                    );
+                    return Ok(());
+                }
+                if !*ARCHIVE_COMMAND_MEDIA && is_command(&msg) {
+                    log::info!("Skip command message {}", msg.id);
+                    return Ok(());
+                }
+                let inflight = match Inflight::admit(&INFLIGHT, *MAX_INFLIGHT) {
+                    Ok(inflight) => inflight,
+                    Err(inflight) => {
+                        log::warn!("Reject message {}, {} messages in flight", msg.id, inflight);
+                        send_reply(
+                            &bot,
+                            msg.chat.id,
+                            Some(msg.id),
+                            topic_id(&msg),
+                            "繁忙，请稍后",
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                };
                 log::debug!("Send message");
-                tx.send(msg).await.unwrap();
+                tx.send((msg, inflight)).await.unwrap();
                 Ok(())
-            }
+            },
+        ));
+    let handler = dptree::entry()
+        .branch(messages)
+        .branch(
+            Update::filter_edited_message().endpoint(|msg: Message| async move {
+                retraction::handle_edit(msg).await;
+                ResponseResult::Ok(())
+            }),
+        )
+        .branch(Update::filter_my_chat_member().endpoint(membership::handle_update));
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
+        .dependencies(dptree::deps![tx.clone()])
+        .default_handler(|_| async {})
+        .enable_ctrlc_handler()
+        .build();
+    match webhook {
+        Some(options) => {
+            log::info!("Receiving updates with a webhook at {}", options.url);
+            let listener = webhooks::axum(bot.clone(), options).await?;
+            let error_handler =
+                LoggingErrorHandler::with_custom_text("An error from the webhook listener");
+            dispatcher
+                .dispatch_with_listener(listener, error_handler)
+                .await;
         }
-    })
-    .await;
+        None => dispatcher.dispatch().await,
+    }
+    // Let the consumer finish the messages already received and stop.
+    drop((dispatcher, tx));
+    heatmap::flush().await;
     consumer.await??;
     Ok(())
 }
 
+/// Webhook address and public URL from WEBHOOK_LISTEN and WEBHOOK_URL.
+fn webhook_options() -> Result<webhooks::Options, String> {
+    let listen = WEBHOOK_LISTEN
+        .as_deref()
+        .ok_or("UPDATE_MODE=webhook needs WEBHOOK_LISTEN")?;
+    let address = listen
+        .parse()
+        .map_err(|e| format!("Invalid WEBHOOK_LISTEN {}: {}", listen, e))?;
+    let url = WEBHOOK_URL
+        .as_deref()
+        .ok_or("UPDATE_MODE=webhook needs WEBHOOK_URL")?;
+    let url = url
+        .parse()
+        .map_err(|e| format!("Invalid WEBHOOK_URL {}: {}", url, e))?;
+    Ok(webhooks::Options::new(address, url))
+}
+
+/// Checks the token with getMe, then tells `admin_chat_id` the bot has started. Runs
+/// beside the dispatcher, so a failure is only logged.
+async fn notify_startup(bot: Arc<Bot>, admin_chat_id: ChatId) {
+    let me = match bot.get_me().await {
+        Ok(me) => me,
+        Err(e) => {
+            log::warn!("getMe error, skip startup notification: {:?}", e);
+            return;
+        }
+    };
+    let text = format!(
+        "@{} 已启动，版本 {}，运行时间从现在重新计算\n\n{}",
+        me.username(),
+        env!("CARGO_PKG_VERSION"),
+        commands::config_reply(None).await
+    );
+    if let Err(e) = bot.send_message(admin_chat_id, text).await {
+        log::warn!("Send startup notification error: {:?}", e);
+    }
+}
+
+/// Moves per-chat state to the new id of a group that became a supergroup. Telegram
+/// sends a migration message to both chats, so this runs twice; the second time
+/// there is nothing left to move.
+async fn migrate_chat(from: ChatId, to: ChatId) {
+    let mut config = RUNTIME_CONFIG.write().await;
+    if config.migrate(from.0, to.0) {
+        log::info!("Chat {} migrated to {}, move its settings", from, to);
+        if let Err(e) = config::save(&config).await {
+            log::warn!("{:?}", e);
+        }
+    }
+    drop(config);
+    let mut last_files = LAST_FILES.lock().await;
+    if let Some(path) = last_files.remove(&from) {
+        last_files.insert(to, path);
+    }
+}
+
+/// Messages being downloaded, by chat and message id. Downloads run side by side, so a
+/// duplicate delivery of an update, like a webhook retried while the first delivery is
+/// still downloading, would otherwise write the same files at the same time.
+static DOWNLOADING: Lazy<std::sync::Mutex<HashSet<(ChatId, MessageId)>>> =
+    Lazy::new(Default::default);
+
+/// A message claimed for download in DOWNLOADING, released on drop.
+struct Claim((ChatId, MessageId));
+
+impl Claim {
+    /// Claims `msg` unless another task is downloading it. Checking and inserting
+    /// happen under one lock, so of two tasks with the same message exactly one wins.
+    fn new(msg: (ChatId, MessageId)) -> Option<Claim> {
+        let mut downloading = DOWNLOADING.lock().unwrap_or_else(|e| e.into_inner());
+        downloading.insert(msg).then(|| Claim(msg))
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let mut downloading = DOWNLOADING.lock().unwrap_or_else(|e| e.into_inner());
+        downloading.remove(&self.0);
+    }
+}
+
+#[derive(Default)]
 struct ConsumerState {
-    reply_chat_id: Option<ChatId>,
-    reply_message_id: Option<MessageId>,
+    /// Statistics waiting to be sent, per source chat.
+    batches: HashMap<ChatId, Batch>,
+    /// Downloads still running per chat. A chat's statistics wait for all of them.
+    pending: HashMap<ChatId, usize>,
+}
+
+struct Batch {
+    /// First message of the batch, which the statistics reply to. Batches are kept per
+    /// chat, so it is always a message of the chat the reply goes to.
+    reply_to: MessageId,
+    reply_thread_id: Option<ThreadId>,
     statics: Vec<String>,
+    /// With ALBUM_REPLY_COMPACT, the line summing up each album, by media group id.
+    albums: HashMap<String, AlbumLine>,
+}
+
+/// Archived and failed items of an album, summed up in `statics[index]`.
+struct AlbumLine {
+    index: usize,
+    photos: usize,
+    videos: usize,
+    failed: usize,
+}
+
+impl AlbumLine {
+    fn line(&self) -> String {
+        let mut kinds = vec![];
+        if self.photos > 0 {
+            kinds.push(format!("{} 图", self.photos));
+        }
+        if self.videos > 0 {
+            kinds.push(format!("{} 视频", self.videos));
+        }
+        let archived = self.photos + self.videos;
+        let mut line = if self.failed > 0 {
+            format!("相册: {} 成功 {} 失败", archived, self.failed)
+        } else {
+            format!("相册: {} 项", archived)
+        };
+        if !kinds.is_empty() {
+            line.push_str(&format!(" ({})", kinds.join(" ")));
+        }
+        line
+    }
+}
+
+impl ConsumerState {
+    /// Adds the statistic line of a message. With ALBUM_REPLY_COMPACT, `album` is the
+    /// media group id of an album item with the type it archived, or `None` if its
+    /// download failed. Such items are counted instead of listed.
+    fn push(
+        &mut self,
+        msg: (ChatId, MessageId, Option<ThreadId>),
+        album: Option<(String, Option<MediaType>)>,
+        line: String,
+    ) {
+        let (chat_id, msg_id, thread_id) = msg;
+        let batch = self.batches.entry(chat_id).or_insert_with(|| Batch {
+            reply_to: msg_id,
+            reply_thread_id: thread_id,
+            statics: vec![],
+            albums: HashMap::new(),
+        });
+        match album {
+            Some((group_id, media_type)) => {
+                let album = batch.albums.entry(group_id).or_insert_with(|| {
+                    batch.statics.push(String::new());
+                    AlbumLine {
+                        index: batch.statics.len() - 1,
+                        photos: 0,
+                        videos: 0,
+                        failed: 0,
+                    }
+                });
+                match media_type {
+                    Some(MediaType::Photo) => album.photos += 1,
+                    Some(MediaType::Video) => album.videos += 1,
+                    None => album.failed += 1,
+                }
+                batch.statics[album.index] = album.line();
+            }
+            None => batch.statics.push(line),
+        }
+    }
+
+    /// Takes out the batches of chats with no download running and no album in
+    /// `settling`, to be replied to without holding the state.
+    fn take_ready(&mut self, settling: &HashSet<ChatId>) -> Vec<(ChatId, Batch)> {
+        let chat_ids: Vec<ChatId> = self
+            .batches
+            .keys()
+            .filter(|chat_id| !self.pending.contains_key(chat_id) && !settling.contains(chat_id))
+            .copied()
+            .collect();
+        chat_ids
+            .into_iter()
+            .filter_map(|chat_id| Some((chat_id, self.batches.remove(&chat_id)?)))
+            .collect()
+    }
+
+    /// Puts back a batch whose reply was deferred. Statistics added while it was out
+    /// come after its own, and the reply still goes to its first message.
+    fn put_back(&mut self, chat_id: ChatId, mut batch: Batch) {
+        if let Some(newer) = self.batches.remove(&chat_id) {
+            let offset = batch.statics.len();
+            batch.statics.extend(newer.statics);
+            for (group_id, mut album) in newer.albums {
+                album.index += offset;
+                batch.albums.insert(group_id, album);
+            }
+        }
+        self.batches.insert(chat_id, batch);
+    }
+
+    /// Marks a download of `chat_id` as finished.
+    fn done(&mut self, chat_id: ChatId) {
+        if let Some(pending) = self.pending.get_mut(&chat_id) {
+            *pending -= 1;
+            if *pending == 0 {
+                self.pending.remove(&chat_id);
+            }
+        }
+    }
 }
 
 /// Consumer loop:
 /// 1. Consumes messages from receiver
 /// 2. After messages drain, waits for seconds(default 2 seconds), reply the statistics to the sender.
+///    Each chat gets its own reply with only its own statistics, once none of its
+///    downloads is running anymore.
+/// 3. Once the dispatcher has stopped and every download finished, sends the remaining
+///    replies, dropping the ones Telegram asks to defer, and returns.
 ///
-async fn consumer_loop(bot: Arc<Bot>, mut receiver: Receiver<Message>) -> ResponseResult<()> {
+async fn consumer_loop(
+    bot: Arc<Bot>,
+    mut receiver: Receiver<(Message, Inflight)>,
+) -> ResponseResult<()> {
     log::info!("Start consumer loop");
-    let state = Arc::new(Mutex::new(ConsumerState {
-        reply_chat_id: None,
-        reply_message_id: None,
-        statics: vec![],
-    }));
+    let state = Arc::new(Mutex::new(ConsumerState::default()));
+    // When the latest item of each album arrived, by chat and media group id.
+    let mut albums: HashMap<(ChatId, String), Instant> = HashMap::new();
+    // Set when Telegram asked to wait longer than MAX_RETRY_AFTER_SECS.
+    let mut deferred_until: Option<Instant> = None;
     loop {
         tokio::select! {
-            Some(msg) = receiver.recv() => {
+            Some((msg, inflight)) = receiver.recv() => {
                 log::info!("Received message: {}", &msg.id);
+                if let Some(group_id) = msg.media_group_id() {
+                    albums.insert((msg.chat.id, group_id.to_string()), Instant::now());
+                }
                 let bot = bot.clone();
                 let state = state.clone();
+                let (chat_id, msg_id, thread_id) = (msg.chat.id, msg.id, topic_id(&msg));
+                // Downloads run side by side; the state is only locked to record them.
                 tokio::spawn(async move {
                     log::debug!("Spawn to handle message");
+                    // Leaves INFLIGHT when the task finishes, however it returns.
+                    let _inflight = inflight;
+                    let Some(_claim) = Claim::new((chat_id, msg_id)) else {
+                        log::info!("Skip message {}, already downloading", msg_id);
+                        return;
+                    };
+                    *state.lock().await.pending.entry(chat_id).or_default() += 1;
+                    let group_id = msg.media_group_id().map(String::from);
+                    let result = download(bot.clone(), msg).await;
+                    if let Err(e) = &result {
+                        *diag::LAST_ERROR.lock().await = Some((Local::now(), format!("{:#}", e)));
+                    }
+                    let category = result
+                        .as_ref()
+                        .err()
+                        .and_then(|e| e.downcast_ref::<DownloadError>())
+                        .map(ToString::to_string);
+                    if let Err(e) = &result {
+                        if category.is_none() {
+                            log::warn!("Download error: {:?}", e);
+                        }
+                        if *REPLY_ON_FAILURE {
+                            let text = format_summary(&[category.clone().unwrap_or(String::from("下载失败"))]);
+                            if let Err(e) = send_reply(&bot, chat_id, Some(msg_id), thread_id, &text).await {
+                                log::warn!("Reply download failure error: {:?}", e);
+                            }
+                        }
+                    }
                     let mut state = state.lock().await;
-                    state.reply_message_id = state.reply_message_id.or(Some(msg.id.clone()));
-                    state.reply_chat_id = state.reply_chat_id.or(msg.chat_id().clone());
-                    if let Ok(response) = download(bot, msg).await {
-                        state.statics.push(response);
+                    match (result, category) {
+                        // Already acknowledged with a reaction.
+                        (Ok(statistic), _) if statistic.line.is_empty() => {}
+                        (Ok(statistic), _) => {
+                            let album = group_id
+                                .filter(|_| *ALBUM_REPLY_COMPACT)
+                                .zip(statistic.archived.map(Some));
+                            state.push((chat_id, msg_id, thread_id), album, statistic.line)
+                        }
+                        (Err(_), category) => match (category, group_id) {
+                            (_, Some(group_id)) if *ALBUM_REPLY_COMPACT => {
+                                state.push((chat_id, msg_id, thread_id), Some((group_id, None)), String::new())
+                            }
+                            (Some(category), _) => state.push((chat_id, msg_id, thread_id), None, category),
+                            _ => {}
+                        },
                     }
+                    state.done(chat_id);
             });
             },
             _ = sleep(Duration::from_secs(RECEIVE_TIMEOUT)) => {
-                let mut state = state.lock().await;
-                match (state.reply_chat_id, state.reply_message_id) {
-                    (Some(chat_id), Some(msg_id)) => {
-                        let response = state.statics.join("\n");
-                        bot.send_message(chat_id, &response)
-                            .reply_to(msg_id)
-                            .await?;
-                        state.statics.clear();
-                        state.reply_chat_id = None;
-                        state.reply_message_id = None;
-                        log::info!("Replied statistics message: {}", response);
+                let quiet = Duration::from_secs(*ALBUM_TIMEOUT_SECS);
+                let settling = settling_chats(&mut albums, Instant::now(), quiet);
+                // Nothing more can arrive, so the last replies are sent right away
+                // instead of waiting out a deferral.
+                let closing = receiver.is_closed()
+                    && receiver.is_empty()
+                    && INFLIGHT.load(Ordering::SeqCst) == 0;
+                if !closing && deferred_until.is_some_and(|until| Instant::now() < until) {
+                    continue;
+                }
+                deferred_until = None;
+                // Replies can sleep through flood-waits, so they are sent without holding
+                // the state, and downloads keep recording their statistics meanwhile.
+                let ready = state.lock().await.take_ready(&settling);
+                let mut deferred = vec![];
+                for (chat_id, batch) in ready {
+                    if deferred_until.is_some() {
+                        deferred.push((chat_id, batch));
+                        continue;
+                    }
+                    let response = batch.statics.join("\n");
+                    match reply_batch(&bot, chat_id, &batch).await {
+                        Ok(()) => log::info!("Replied statistics message to chat {}: {}", chat_id, response),
+                        Err(RequestError::RetryAfter(secs)) if closing => log::warn!(
+                            "Retry after {}s while stopping, drop statistics of chat {}:\n{}",
+                            secs.seconds(),
+                            chat_id,
+                            response
+                        ),
+                        Err(RequestError::RetryAfter(secs)) => {
+                            log::warn!(
+                                "Retry after {}s exceeds MAX_RETRY_AFTER_SECS, defer statistics reply",
+                                secs.seconds()
+                            );
+                            deferred_until = Some(Instant::now() + secs.duration());
+                            deferred.push((chat_id, batch));
+                        }
+                        // The bot was removed, the message deleted and the like. Retrying
+                        // won't help, and the other chats still get their replies.
+                        Err(e) => log::warn!(
+                            "Reply statistics to chat {} error, drop them: {:?}\n{}",
+                            chat_id,
+                            e,
+                            response
+                        ),
                     }
-                    _ => {}
+                }
+                let mut state = state.lock().await;
+                for (chat_id, batch) in deferred {
+                    state.put_back(chat_id, batch);
+                }
+                if closing && state.batches.is_empty() {
+                    log::info!("Stop consumer loop");
+                    return Ok(());
                 }
                 continue;
             }
@@ -117,80 +898,1886 @@ async fn consumer_loop(bot: Arc<Bot>, mut receiver: Receiver<Message>) -> Respon
     }
 }
 
-async fn download(bot: Arc<Bot>, msg: Message) -> Result<String> {
+/// Chats whose summary waits because one of their `albums` got an item less than
+/// `quiet` ago, i.e. ALBUM_TIMEOUT_SECS. Albums quiet for longer are forgotten. Other
+/// messages don't end the wait, so an album stays in one summary however its items
+/// interleave with the rest of the chat.
+fn settling_chats(
+    albums: &mut HashMap<(ChatId, String), Instant>,
+    now: Instant,
+    quiet: Duration,
+) -> HashSet<ChatId> {
+    albums.retain(|_, received_at| now.duration_since(*received_at) < quiet);
+    albums.keys().map(|(chat_id, _)| *chat_id).collect()
+}
+
+/// Sends the statistics of `batch` from `chat_id`, to SUMMARY_ADMIN_CHAT when set.
+async fn reply_batch(bot: &Bot, chat_id: ChatId, batch: &Batch) -> ResponseResult<()> {
+    match *SUMMARY_ADMIN_CHAT {
+        Some(admin_chat_id) => {
+            let mut lines = vec![format!("来自聊天 {}:", chat_id)];
+            lines.extend(summary_lines(&batch.statics, *MAX_ITEMS_PER_REPLY));
+            send_reply(bot, admin_chat_id, None, None, &format_summary(&lines)).await
+        }
+        None => {
+            let response = format_summary(&summary_lines(&batch.statics, *MAX_ITEMS_PER_REPLY));
+            let thread_id = batch.reply_thread_id;
+            send_reply(bot, chat_id, Some(batch.reply_to), thread_id, &response).await
+        }
+    }
+}
+
+/// Caps the per-file lines at `max`, i.e. MAX_ITEMS_PER_REPLY, while keeping the total
+/// accurate.
+fn summary_lines(statics: &[String], max: Option<usize>) -> Vec<String> {
+    match max {
+        Some(max) if statics.len() > max => {
+            let mut lines = vec![format!("共 {} 项:", statics.len())];
+            lines.extend(statics[..max].iter().cloned());
+            lines.push(format!("... 以及 {} 个更多", statics.len() - max));
+            lines
+        }
+        _ => statics.to_vec(),
+    }
+}
+
+/// Joins the statistics into one reply, escaped for SUMMARY_PARSE_MODE.
+fn format_summary(statics: &[String]) -> String {
+    statics
+        .iter()
+        .map(|line| match *SUMMARY_PARSE_MODE {
+            Some(ParseMode::MarkdownV2) => markdown::escape(line),
+            Some(ParseMode::Html) => html::escape(line),
+            _ => line.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Sends a message, as a reply when `reply_to` is set, sleeping through flood-waits
+/// up to MAX_RETRY_AFTER_SECS. A longer RetryAfter is returned to the caller so it can
+/// defer the reply.
+async fn send_reply(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: Option<MessageId>,
+    thread_id: Option<ThreadId>,
+    text: &str,
+) -> ResponseResult<()> {
+    loop {
+        let mut request = bot.send_message(chat_id, text);
+        if let Some(msg_id) = reply_to {
+            request = request.reply_to(msg_id);
+        }
+        request.message_thread_id = thread_id;
+        request.parse_mode = *SUMMARY_PARSE_MODE;
+        match request.await {
+            Ok(_) => return Ok(()),
+            Err(RequestError::RetryAfter(secs)) if secs.seconds() <= *MAX_RETRY_AFTER_SECS => {
+                log::debug!("Retry after {}s", secs.seconds());
+                sleep(secs.duration()).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn sender_allowed(msg: &Message) -> bool {
+    sender_in(msg, &ALLOWED_USER_IDS, *ALLOW_CHAT_SENDERS)
+}
+
+/// Whether the sender of `msg` is one of `allowed`, everyone when it's empty. Messages
+/// sent on behalf of a chat have no user to check and follow `allow_chat_senders`.
+fn sender_in(msg: &Message, allowed: &[UserId], allow_chat_senders: bool) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+    match (&msg.sender_chat, &msg.from) {
+        (Some(_), _) | (None, None) => allow_chat_senders,
+        (None, Some(user)) => allowed.contains(&user.id),
+    }
+}
+
+/// Known download failures that are reported back to the sender as a statistic.
+#[derive(Debug, thiserror::Error)]
+enum DownloadError {
+    /// Bot API refuses to serve the file, which happens for files over its 20MB limit.
+    #[error("文件不可下载（过大）")]
+    TooBig,
+    /// The file downloaded to zero bytes, even after one retry.
+    #[error("下载为空")]
+    Empty,
+    /// The downloaded size kept differing from the size Telegram reported.
+    #[error("下载不完整")]
+    Truncated,
+}
+
+/// Gets the file info, failing with [`DownloadError::TooBig`] if the Bot API leaves
+/// the file without a downloadable path, see [`with_download_path`].
+///
+/// Network errors and flood-waits up to MAX_RETRY_AFTER_SECS are retried following
+/// GET_FILE_RETRY. Other API errors, like an invalid file id, won't change on retry.
+async fn get_file(source: &impl FileSource, file_id: &str) -> Result<TgFile> {
+    let mut attempt = 1;
+    let file = loop {
+        let error = match source.get_file(file_id).await {
+            Ok(file) => break file,
+            Err(RequestError::Api(ApiError::Unknown(e))) if e.contains("file is too big") => {
+                log::warn!("File {} is too big: {}", file_id, e);
+                return Err(DownloadError::TooBig.into());
+            }
+            Err(e) => e,
+        };
+        if let RequestError::RetryAfter(secs) = &error {
+            throttle::rate_limited(secs.duration());
+        }
+        let delay = match &error {
+            RequestError::RetryAfter(secs) if secs.seconds() <= *MAX_RETRY_AFTER_SECS => {
+                secs.duration().max(GET_FILE_RETRY.delay(attempt))
+            }
+            RequestError::Network(_) | RequestError::Io(_) => GET_FILE_RETRY.delay(attempt),
+            _ => return Err(error.into()),
+        };
+        if attempt >= GET_FILE_RETRY.attempts {
+            return Err(error.into());
+        }
+        log::warn!(
+            "get_file {} error (attempt {}/{}), retrying in {:?}: {}",
+            file_id,
+            attempt,
+            GET_FILE_RETRY.attempts,
+            delay,
+            error
+        );
+        sleep(delay).await;
+        attempt += 1;
+    };
+    Ok(with_download_path(file)?)
+}
+
+/// Returns `file` if it has a path to download it from. For very large files the Bot
+/// API leaves it empty, and a URL built from that fails obscurely, so that is reported
+/// as [`DownloadError::TooBig`] without downloading.
+fn with_download_path(file: TgFile) -> Result<TgFile, DownloadError> {
+    if file.path.is_empty() {
+        log::warn!("File {} has no download path", file.id);
+        return Err(DownloadError::TooBig);
+    }
+    Ok(file)
+}
+
+/// Statistic line of one message, and the media type it archived, if any.
+struct Statistic {
+    /// Empty when the download was acknowledged with a reaction instead.
+    line: String,
+    archived: Option<MediaType>,
+}
+
+impl Statistic {
+    fn archived(line: String, media_type: MediaType) -> Self {
+        Statistic {
+            line,
+            archived: Some(media_type),
+        }
+    }
+}
+
+impl From<String> for Statistic {
+    fn from(line: String) -> Self {
+        Statistic {
+            line,
+            archived: None,
+        }
+    }
+}
+
+impl From<&str> for Statistic {
+    fn from(line: &str) -> Self {
+        line.to_string().into()
+    }
+}
+
+/// Downloads the media of `msg` into its archive folder and returns its statistic.
+///
+/// A message carries exactly one media kind (teloxide's `MediaKind`), so at most one
+/// branch below matches. Of a photo's sizes only the largest is kept. Anything other
+/// than a photo or video is not downloaded.
+async fn download(bot: Arc<Bot>, msg: Message) -> Result<Statistic> {
     log::info!("Handling message: {}", &msg.id);
-    let path = DOWNLOAD_DIR.join(Local::now().format("%Y-%m-%d").to_string());
-    ensure_dir_exists(&path)?;
+    if PAUSED.load(Ordering::SeqCst) {
+        log::info!("Downloads paused, skip message: {}", &msg.id);
+        return Ok("下载已暂停，已跳过".into());
+    }
+    if skips_forward(&msg, *SKIP_FORWARDS) {
+        log::info!("Skip forwarded message: {}", &msg.id);
+        return Ok("转发内容已跳过".into());
+    }
+    let root = RUNTIME_CONFIG.read().await.download_dir(msg.chat.id.0);
+    // Created by create_file once something is written, so skipped messages leave no
+    // empty sender or topic folders behind.
+    let dir = archive_dir(&root, today(), &Placement::of(&msg));
+    // With ENCRYPTION_KEY, media is downloaded and post-processed outside the archive,
+    // which only receives it encrypted.
+    let work_dir = crypto::staging_dir(msg.chat.id).unwrap_or_else(|| dir.clone());
+    if let Some(min) = *MIN_FREE_INODES
+        && let Some(existing) = dir.ancestors().find(|dir| dir.exists())
+        && let Ok(diag::DiskFree {
+            inodes: Some(inodes),
+            ..
+        }) = diag::disk_free(existing)
+        && inodes < min
+    {
+        log::warn!("Only {} inodes left, skip message {}", inodes, &msg.id);
+        return Ok("inode 不足，已跳过".into());
+    }
+    let mut archive = ArchiveInfo::new();
+    if let Some(topic_id) = topic_id(&msg) {
+        archive.insert("topic_id".into(), topic_id.0.0.into());
+    }
+    if let Some(caption) = caption_html(&msg, *PRESERVE_CAPTION_LINKS) {
+        archive.insert("caption_html".into(), caption.into());
+    }
+    if *SAVE_REPLY_CONTEXT && let Some(reply) = msg.reply_to_message() {
+        archive.insert("reply_to".into(), reply_context(reply));
+    }
+    let link = EMBED_MESSAGE_LINK.then(|| msg.url()).flatten();
+    if let Some(link) = &link {
+        archive.insert("link".into(), link.as_str().into());
+    }
+    let link_suffix = link.map(|link| format!(" {}", link)).unwrap_or_default();
+
+    if let Some(kind) = unsupported_media(&msg.kind) {
+        log::info!("Message {} carries unsupported media: {}", &msg.id, kind);
+        if *ARCHIVE_UNKNOWN_MEDIA {
+            archive.insert("unsupported_media".into(), kind.into());
+            save_message(&msg, &dir, &format!("{}_{}", kind, msg.id), &archive).await;
+            return Ok(format!("已记录不支持的媒体 {}", kind).into());
+        }
+    }
+
+    if !matches!(msg.kind, MessageKind::Common(_) | MessageKind::Empty {}) {
+        if !*ARCHIVE_SERVICE_MESSAGES {
+            return Ok("No media download".into());
+        }
+        archive.insert("service".into(), true.into());
+        save_message(&msg, &dir, &format!("service_{}", msg.id), &archive).await;
+        return Ok("已记录服务消息".into());
+    }
+
+    let config = RUNTIME_CONFIG.read().await;
+    let (photos, videos) = (
+        config.downloads(MediaType::Photo),
+        config.downloads(MediaType::Video),
+    );
+    drop(config);
+
+    if (msg.photo().is_some() || msg.video().is_some()) && !caption_matches(&msg) {
+        log::info!("Skip message {}, caption doesn't match", &msg.id);
+        return Ok("标题不匹配，已跳过".into());
+    }
 
     if let Some(photo) = msg.photo().and_then(|p| p.last()) {
+        if !photos {
+            return Ok("图片下载未启用，已跳过".into());
+        }
         let file_id = &photo.file.id;
-        let file = bot.get_file(file_id).await?;
-        let file_name = format!("photo_{}.jpg", file_id);
-        let path = path.join(&file_name);
-        let dst_file = File::create(&path)
-            .await
-            .map_err(|e| RequestError::Io(Arc::new(e)))?;
-        let mut dst_file = BufWriter::new(dst_file);
+        let file_name = media_file_name("photo", &photo.file, msg.id, "jpg");
+        let path = work_dir.join(&file_name);
         log::debug!("Downloading photo: {}", &file_id);
-        bot.download_file(&file.path, &mut dst_file).await?;
+        transfer(&*bot, file_id, &path, msg.media_group_id().is_some()).await?;
         log::info!("Downloaded photo: {:?}", &path);
-        save_message(&msg, &file_name).await;
-        return Ok(format!("下载图片{}成功", &photo.file.unique_id));
+        let file_name = verify_media_type(&work_dir, file_name, &mut archive).await?;
+        let path = work_dir.join(&file_name);
+        if watermark::enabled() {
+            let photo_path = path.clone();
+            let _permit = HOOK_PERMITS.acquire().await?;
+            match tokio::task::spawn_blocking(move || watermark::watermark_photo(&photo_path))
+                .await?
+            {
+                Ok(watermarked) => {
+                    archive.insert("watermarked".into(), watermarked.into());
+                    keep_original(&path, &mut archive);
+                }
+                Err(e) => log::warn!("Watermark {:?} error: {:?}", &path, e),
+            }
+        }
+        finish_download(&msg, &work_dir, &dir, &file_name, archive).await?;
+        let line = if acknowledge(&bot, &msg).await {
+            String::new()
+        } else {
+            format!("下载图片{}成功{}", &photo.file.unique_id, link_suffix)
+        };
+        return Ok(Statistic::archived(line, MediaType::Photo));
     }
 
     if let Some(video) = msg.video() {
+        if !videos {
+            return Ok("视频下载未启用，已跳过".into());
+        }
         let file_id = &video.file.id;
-        let file = bot.get_file(file_id).await?;
-        let file_name = &video
-            .file_name
-            .clone()
-            .or(Some(format!("video_{}.mp4", file_id)))
-            .unwrap();
-        let path = path.join(&file_name);
-        let mut dst_file = File::create(&path)
-            .await
-            .map_err(|e| RequestError::Io(Arc::new(e)))?;
+        let extension = video
+            .mime_type
+            .as_ref()
+            .and_then(|mime| mime_extension(mime.essence_str()))
+            .unwrap_or("mp4");
+        let mut file_name = match video.file_name.as_deref() {
+            Some(name) => sender_file_name(name, &video.file, msg.id, extension),
+            None => media_file_name("video", &video.file, msg.id, extension),
+        };
+        let path = work_dir.join(&file_name);
         log::debug!("Downloading video: {}", &file_id);
-        bot.download_file(&file.path, &mut dst_file).await?;
+        transfer(&*bot, file_id, &path, msg.media_group_id().is_some()).await?;
         log::info!("Downloaded video: {:?}", path);
-        save_message(&msg, &file_name).await;
-        return Ok(format!("下载视频{}成功", file_name));
+        file_name = verify_media_type(&work_dir, file_name, &mut archive).await?;
+        let path = work_dir.join(&file_name);
+        if transcode::enabled() {
+            let _permit = HOOK_PERMITS.acquire().await?;
+            match transcode::transcode_video(&path).await {
+                Ok(output) => {
+                    archive.insert(
+                        "transcoded".into(),
+                        serde_json::json!({ "from": &file_name }),
+                    );
+                    keep_original(&path, &mut archive);
+                    file_name = output.file_name().unwrap().to_string_lossy().into_owned();
+                }
+                Err(e) => log::warn!("Transcode {:?} error: {:?}", &path, e),
+            }
+        }
+        finish_download(&msg, &work_dir, &dir, &file_name, archive).await?;
+        let line = if acknowledge(&bot, &msg).await {
+            String::new()
+        } else {
+            format!("下载视频{}成功{}", file_name, link_suffix)
+        };
+        return Ok(Statistic::archived(line, MediaType::Video));
     }
 
-    Ok(String::from("No media download"))
+    Ok("No media download".into())
 }
 
-async fn save_message(msg: &Message, file_name: &String) {
-    let path = DOWNLOAD_DIR
-        .join(Local::now().format("%Y-%m-%d").to_string())
-        .join(file_name.to_owned() + ".json");
-    let msg_json = serde_json::to_string_pretty(&msg)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
-    let msg_file = File::create(&path).await;
-
-    match (&msg_file, &msg_json) {
-        (Ok(_), Ok(msg_json)) => {
-            let mut msg_file = msg_file.unwrap();
-            if let Err(e) = msg_file.write_all(msg_json.as_bytes()).await {
-                log::warn!("Save json {:?} error: {:?}", &path, e);
-            } else {
-                log::debug!("Save json {:?} successfully.", &path);
-            }
-        }
-        _ => {
-            log::warn!(
-                "Save message error: message: {:?}, file:{:?}",
-                &msg_file,
-                &msg_json
-            );
-        }
+/// Name of an attachment the bot doesn't download, as the field Telegram sends it in.
+/// `None` for text, for the photos and videos handled by [`download`] and for service
+/// messages.
+///
+/// Attachments newer than teloxide leave a message with no content it knows, which it
+/// parses as [`MessageKind::Empty`]. Their fields are dropped while parsing, so they are
+/// only reported as `unknown`.
+fn unsupported_media(kind: &MessageKind) -> Option<&'static str> {
+    let media_kind = match kind {
+        MessageKind::Common(common) => &common.media_kind,
+        MessageKind::Empty {} => return Some("unknown"),
+        _ => return None,
+    };
+    match media_kind {
+        MediaKind::Text(_) | MediaKind::Photo(_) | MediaKind::Video(_) => None,
+        MediaKind::Migration(_) => None,
+        MediaKind::Animation(_) => Some("animation"),
+        MediaKind::Audio(_) => Some("audio"),
+        MediaKind::Contact(_) => Some("contact"),
+        MediaKind::Document(_) => Some("document"),
+        MediaKind::Game(_) => Some("game"),
+        MediaKind::Venue(_) => Some("venue"),
+        MediaKind::Location(_) => Some("location"),
+        MediaKind::Poll(_) => Some("poll"),
+        MediaKind::Sticker(_) => Some("sticker"),
+        MediaKind::Story(_) => Some("story"),
+        MediaKind::VideoNote(_) => Some("video_note"),
+        MediaKind::Voice(_) => Some("voice"),
     }
 }
 
-fn ensure_dir_exists(path: &Path) -> Result<()> {
-    if !path.exists() {
-        std::fs::create_dir_all(path)
-            .with_context(|| format!("Create download dir error: {}", path.display()))?;
+/// With VERIFY_MEDIA_TYPE, renames the downloaded `file_name` when its content is of
+/// another type than its extension says, and records the declared extension.
+/// Returns the name the file has afterwards.
+async fn verify_media_type(
+    dir: &Path,
+    file_name: String,
+    archive: &mut ArchiveInfo,
+) -> Result<String> {
+    if !*VERIFY_MEDIA_TYPE {
+        return Ok(file_name);
+    }
+    let path = dir.join(&file_name);
+    let mut head = [0u8; 64];
+    let mut file = File::open(&path).await?;
+    let read = file.read(&mut head).await?;
+    let Some(actual) = sniff_extension(&head[..read]) else {
+        return Ok(file_name);
+    };
+    let declared = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if same_media_type(&declared, actual) {
+        return Ok(file_name);
+    }
+    let renamed = path.with_extension(actual);
+    if renamed.exists() {
+        log::warn!(
+            "{:?} looks like {}, but {:?} already exists",
+            &path,
+            actual,
+            &renamed
+        );
+        return Ok(file_name);
+    }
+    tokio::fs::rename(&path, &renamed).await?;
+    log::warn!(
+        "{:?} looks like {}, renamed to {:?}",
+        &path,
+        actual,
+        &renamed
+    );
+    archive.insert("declared_extension".into(), declared.into());
+    Ok(renamed.file_name().unwrap().to_string_lossy().into_owned())
+}
+
+/// Extensions of the ISO base media (`ftyp`) family. Their brands are often generic,
+/// like `isom`, so any of them is accepted for any other.
+const FTYP_EXTENSIONS: [&str; 5] = ["mp4", "m4v", "mov", "3gp", "3g2"];
+
+/// Whether a file named with the `declared` extension holds `actual` content.
+fn same_media_type(declared: &str, actual: &str) -> bool {
+    declared == actual
+        || (declared == "jpeg" && actual == "jpg")
+        || (FTYP_EXTENSIONS.contains(&declared) && FTYP_EXTENSIONS.contains(&actual))
+}
+
+/// Guesses the extension of a media file from its first bytes.
+fn sniff_extension(head: &[u8]) -> Option<&'static str> {
+    match head {
+        [0xFF, 0xD8, 0xFF, ..] => Some("jpg"),
+        [0x89, b'P', b'N', b'G', ..] => Some("png"),
+        [b'G', b'I', b'F', b'8', ..] => Some("gif"),
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'W',
+            b'E',
+            b'B',
+            b'P',
+            ..,
+        ] => Some("webp"),
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'A',
+            b'V',
+            b'I',
+            b' ',
+            ..,
+        ] => Some("avi"),
+        [
+            _,
+            _,
+            _,
+            _,
+            b'f',
+            b't',
+            b'y',
+            b'p',
+            b'q',
+            b't',
+            b' ',
+            b' ',
+            ..,
+        ] => Some("mov"),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'3', b'g', b'2', ..] => Some("3g2"),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'3', b'g', ..] => Some("3gp"),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'M', b'4', b'V', ..] => Some("m4v"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("mp4"),
+        [0x1A, 0x45, 0xDF, 0xA3, ..] if head.windows(4).any(|w| w == b"webm") => Some("webm"),
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => Some("mkv"),
+        _ => None,
+    }
+}
+
+/// Extension for a media MIME type, for files sent without one in their name.
+fn mime_extension(mime: &str) -> Option<&'static str> {
+    match mime {
+        "image/jpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        "video/mp4" => Some("mp4"),
+        "video/quicktime" => Some("mov"),
+        "video/webm" => Some("webm"),
+        "video/x-matroska" => Some("mkv"),
+        "video/x-msvideo" => Some("avi"),
+        "video/mpeg" => Some("mpeg"),
+        "video/3gpp" => Some("3gp"),
+        _ => None,
+    }
+}
+
+/// Characters of the replied-to text kept by SAVE_REPLY_CONTEXT.
+const REPLY_SNIPPET_CHARS: usize = 100;
+
+fn reply_context(reply: &Message) -> serde_json::Value {
+    let snippet = reply
+        .text()
+        .or(reply.caption())
+        .map(|text| text.chars().take(REPLY_SNIPPET_CHARS).collect::<String>());
+    serde_json::json!({
+        "message_id": reply.id.0,
+        "from": reply.from.as_ref().map(|user| user.id.0),
+        "snippet": snippet,
+    })
+}
+
+/// Resolves `file_id` and downloads it to `path`: the part of a download that talks to
+/// Telegram. With DOWNLOAD_CONCURRENCY_MAX it first waits for a slot, in
+/// DOWNLOAD_PRIORITY order for an `album` item or a single message, and its outcome
+/// tunes the limit. Messages that transfer nothing never take a slot.
+async fn transfer(source: &impl FileSource, file_id: &str, path: &Path, album: bool) -> Result<()> {
+    let permit = throttle::acquire(album).await;
+    let result = match get_file(source, file_id).await {
+        Ok(file) => download_to(source, file_id, file, path).await,
+        Err(e) => Err(e),
+    };
+    if let Some(permit) = permit {
+        permit.finish(result.is_ok());
+    }
+    result
+}
+
+/// Pause before downloading a file again that came back empty or truncated.
+const EMPTY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Downloads `file` to `path`. An empty result is usually transient, so it is retried
+/// once; if it stays empty, the file is removed and [`DownloadError::Empty`] returned.
+/// A download smaller or larger than the size Telegram reported is retried up to
+/// TRUNCATED_RETRIES times before [`DownloadError::Truncated`] is returned.
+///
+/// Network errors are retried following DOWNLOAD_RETRY. A client error status means
+/// the download path has expired, so it is refreshed with get_file before the retry.
+async fn download_to(
+    source: &impl FileSource,
+    file_id: &str,
+    mut file: TgFile,
+    path: &Path,
+) -> Result<()> {
+    let (mut attempt, mut empty_retried, mut truncated_retries) = (1, false, 0);
+    loop {
+        let error = match download_once(source, &file.path, path).await {
+            Ok(0) if !empty_retried => {
+                log::warn!("Downloaded empty file {:?}, retrying", path);
+                empty_retried = true;
+                sleep(EMPTY_RETRY_DELAY).await;
+                continue;
+            }
+            Ok(0) => {
+                tokio::fs::remove_file(path).await?;
+                return Err(DownloadError::Empty.into());
+            }
+            Ok(written) if file.size > 0 && written != u64::from(file.size) => {
+                if truncated_retries >= *TRUNCATED_RETRIES {
+                    tokio::fs::remove_file(path).await?;
+                    return Err(DownloadError::Truncated.into());
+                }
+                truncated_retries += 1;
+                log::warn!(
+                    "Downloaded {} of {} bytes to {:?}, retrying ({}/{})",
+                    written,
+                    file.size,
+                    path,
+                    truncated_retries,
+                    *TRUNCATED_RETRIES
+                );
+                sleep(EMPTY_RETRY_DELAY).await;
+                continue;
+            }
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        let TransferError::Network { status, .. } = error else {
+            return Err(error.into());
+        };
+        if status == Some(429) {
+            throttle::rate_limited(DOWNLOAD_RETRY.delay(attempt));
+        }
+        if attempt >= DOWNLOAD_RETRY.attempts {
+            let _ = tokio::fs::remove_file(path).await;
+            return Err(error.into());
+        }
+        let delay = DOWNLOAD_RETRY.delay(attempt);
+        log::warn!(
+            "download_file {:?} error (attempt {}/{}), retrying in {:?}: {}",
+            path,
+            attempt,
+            DOWNLOAD_RETRY.attempts,
+            delay,
+            error
+        );
+        let expired = status.is_some_and(|status| (400..500).contains(&status));
+        sleep(delay).await;
+        if expired {
+            log::info!("Refreshing download path of file {}", file_id);
+            file = get_file(source, file_id).await?;
+        }
+        attempt += 1;
+    }
+}
+
+/// One download of `file_path` to `path`, returning the bytes written.
+async fn download_once(
+    source: &impl FileSource,
+    file_path: &str,
+    path: &Path,
+) -> Result<u64, TransferError> {
+    let dst_file = create_file(path).await.map_err(anyhow::Error::from)?;
+    let mut dst_file = BufWriter::new(dst_file);
+    source.download_file(file_path, &mut dst_file).await?;
+    dst_file.flush().await.map_err(anyhow::Error::from)?;
+    let written = dst_file.get_ref().metadata().await;
+    Ok(written.map_err(anyhow::Error::from)?.len())
+}
+
+/// Where files are fetched from: the Bot API, or a fake one in tests.
+trait FileSource {
+    async fn get_file(&self, file_id: &str) -> Result<TgFile, RequestError>;
+
+    /// Writes the file at `file_path`, as returned by get_file, to `dst`.
+    async fn download_file(
+        &self,
+        file_path: &str,
+        dst: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<(), TransferError>;
+}
+
+/// A failed download_file attempt.
+#[derive(Debug, thiserror::Error)]
+enum TransferError {
+    /// A network error, which is retried, with the HTTP status Telegram answered with.
+    #[error("{error}")]
+    Network {
+        error: anyhow::Error,
+        status: Option<u16>,
+    },
+    /// Writing the file failed, which a retry won't fix.
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl FileSource for Bot {
+    async fn get_file(&self, file_id: &str) -> Result<TgFile, RequestError> {
+        Requester::get_file(self, file_id).await
+    }
+
+    async fn download_file(
+        &self,
+        file_path: &str,
+        dst: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<(), TransferError> {
+        match Download::download_file(self, file_path, dst).await {
+            Ok(()) => Ok(()),
+            Err(TeloxideDownloadError::Network(network)) => Err(TransferError::Network {
+                status: network.status().map(|status| status.as_u16()),
+                error: TeloxideDownloadError::Network(network).into(),
+            }),
+            Err(e) => Err(TransferError::Other(e.into())),
+        }
+    }
+}
+
+/// Reacts to `msg` when REACT_INSTEAD_OF_REPLY is set. Returns whether it did.
+async fn acknowledge(bot: &Bot, msg: &Message) -> bool {
+    if !*REACT_INSTEAD_OF_REPLY {
+        return false;
+    }
+    let mut request = bot.set_message_reaction(msg.chat.id, msg.id);
+    request.reaction = Some(vec![ReactionType::Emoji {
+        emoji: REACTION_EMOJI.clone(),
+    }]);
+    request
+        .await
+        .inspect_err(|e| log::warn!("React to message {} error, reply instead: {:?}", msg.id, e))
+        .is_ok()
+}
+
+/// Default name of a downloaded file: `<kind>_<unique_id>_<message_id>.<ext>`.
+///
+/// `file.id` is bot-specific and is shared by every message forwarding the same file,
+/// so naming by it made different messages overwrite each other. `unique_id` is the
+/// stable id of the content, and the message id keeps separate messages apart.
+fn media_file_name(kind: &str, file: &FileMeta, msg_id: MessageId, extension: &str) -> String {
+    format!("{}_{}_{}.{}", kind, file.unique_id, msg_id, extension)
+}
+
+/// A sender-provided file name made unique like [`media_file_name`]:
+/// `<stem>_<unique_id>_<message_id>.<ext>`, so the same name sent twice on a day, or
+/// the same file forwarded twice, doesn't overwrite an earlier download. `extension` is
+/// used when the name has none.
+fn sender_file_name(name: &str, file: &FileMeta, msg_id: MessageId, extension: &str) -> String {
+    let name = Path::new(name);
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name
+        .extension()
+        .map(|e| e.to_string_lossy())
+        .unwrap_or(extension.into());
+    safe_file_name(&format!(
+        "{}_{}_{}.{}",
+        stem, file.unique_id, msg_id, extension
+    ))
+}
+
+/// Longest file name we create, leaving room under the usual 255-byte limit for the
+/// `.original`/`.json.gz` suffixes added next to it.
+const MAX_FILE_NAME_BYTES: usize = 200;
+
+/// Makes a sender-provided file name safe to create inside the archive folder.
+///
+/// Path separators and control characters are replaced, so the name can't escape the
+/// folder. Names over MAX_FILE_NAME_BYTES are cut on a character boundary, keeping the
+/// extension, and get a hash of the full name so different long names don't collide.
+pub fn safe_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim().trim_start_matches('.');
+    if name.is_empty() {
+        return String::from("unnamed");
+    }
+    if name.len() <= MAX_FILE_NAME_BYTES {
+        return name.to_owned();
+    }
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && extension.len() <= 16 => {
+            (stem, format!(".{}", extension))
+        }
+        _ => (name, String::new()),
+    };
+    // FNV-1a, so the suffix for a given name stays the same across builds.
+    let hash = name.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    });
+    let suffix = format!("_{:08x}{}", hash, extension);
+    let mut end = MAX_FILE_NAME_BYTES - suffix.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], suffix)
+}
+
+/// Steps shared by every media type once its file is written to `work_dir`: encryption
+/// into `dir`, fsync and finally the sidecar. Without encryption, `work_dir` is `dir`.
+async fn finish_download(
+    msg: &Message,
+    work_dir: &Path,
+    dir: &Path,
+    file_name: &String,
+    mut archive: ArchiveInfo,
+) -> Result<()> {
+    let path = dir.join(file_name);
+    if crypto::enabled() {
+        let original = archive
+            .get("original")
+            .and_then(|o| o.as_str())
+            .map(String::from);
+        let encrypted = async {
+            if let Some(original) = &original {
+                crypto::encrypt_to(work_dir.join(original), dir.join(original)).await?;
+            }
+            crypto::encrypt_to(work_dir.join(file_name), path.clone()).await
+        }
+        .await;
+        // The staged plaintext goes either way.
+        for staged in original.iter().chain([file_name]) {
+            let _ = tokio::fs::remove_file(work_dir.join(staged)).await;
+        }
+        archive.insert("encryption".into(), encrypted?);
+    }
+    if *FSYNC_WRITES {
+        File::open(&path).await?.sync_all().await?;
+    }
+    save_message(msg, dir, file_name, &archive).await;
+    LAST_FILES.lock().await.insert(msg.chat.id, path.clone());
+    heatmap::record(msg.chat.id).await;
+    zipme::collect(msg.chat.id, path).await;
+    Ok(())
+}
+
+/// Where post-download steps keep the untouched download: `<name>.original.<ext>`.
+pub fn original_path(path: &Path) -> PathBuf {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("bin");
+    path.with_extension(format!("original.{}", extension))
+}
+
+/// Records the kept original of `path` in the sidecar, if a post-download step made one.
+fn keep_original(path: &Path, archive: &mut ArchiveInfo) {
+    let original = original_path(path);
+    if original.exists() {
+        let name = original.file_name().unwrap().to_string_lossy().into_owned();
+        archive.insert("original".into(), name.into());
+    }
+}
+
+/// Details about how a file was archived, saved under the `archive` key of its sidecar.
+type ArchiveInfo = serde_json::Map<String, serde_json::Value>;
+
+/// Folder a message's media is saved in under `root`: the folder of `day`, plus the
+/// topic folder when GROUP_BY_TOPIC is set and the message comes from a forum group.
+/// Messages in the General topic carry no thread id and go to the `general` folder.
+/// With GROUP_BY_SENDER, the sender folder comes last.
+fn archive_dir(root: &Path, day: NaiveDate, placement: &Placement) -> PathBuf {
+    let day_dir = root.join(day.format("%Y-%m-%d").to_string());
+    grouped_dir(
+        day_dir,
+        placement,
+        *GROUP_BY_TOPIC,
+        GROUP_BY_SENDER.as_deref(),
+    )
+}
+
+/// What the folder of a message's media depends on besides its day, so messages the
+/// bot didn't receive itself, like those of an imported export, go where it would have
+/// put them.
+pub struct Placement {
+    pub topic_id: Option<ThreadId>,
+    pub forum: bool,
+    /// Id and username of the sending user, `None` for channels and anonymous admins.
+    pub sender: Option<(UserId, Option<String>)>,
+}
+
+impl Placement {
+    pub fn of(msg: &Message) -> Self {
+        Placement {
+            topic_id: topic_id(msg),
+            forum: is_forum(&msg.chat),
+            sender: msg
+                .from
+                .as_ref()
+                .filter(|_| msg.sender_chat.is_none())
+                .map(|user| (user.id, user.username.clone())),
+        }
+    }
+}
+
+/// The topic and sender folders of [`archive_dir`] under `dir`.
+fn grouped_dir(
+    mut dir: PathBuf,
+    placement: &Placement,
+    by_topic: bool,
+    by_sender: Option<&str>,
+) -> PathBuf {
+    if by_topic {
+        match placement.topic_id {
+            Some(topic_id) => dir.push(format!("topic_{}", topic_id)),
+            None if placement.forum => dir.push("general"),
+            None => {}
+        }
+    }
+    if let Some(mode) = by_sender {
+        dir.push(sender_folder(placement, mode == "username"));
+    }
+    dir
+}
+
+fn sender_folder(placement: &Placement, by_username: bool) -> String {
+    let Some((id, username)) = &placement.sender else {
+        return String::from("anonymous");
+    };
+    match username {
+        Some(username) if by_username => safe_file_name(username),
+        _ => id.to_string(),
+    }
+}
+
+fn is_forum(chat: &Chat) -> bool {
+    matches!(
+        &chat.kind,
+        ChatKind::Public(ChatPublic {
+            kind: PublicChatKind::Supergroup(PublicChatSupergroup { is_forum: true, .. }),
+            ..
+        })
+    )
+}
+
+/// Whether `msg` is skipped as a forward, which only happens with `skip_forwards`, i.e.
+/// SKIP_FORWARDS.
+fn skips_forward(msg: &Message, skip_forwards: bool) -> bool {
+    skip_forwards && msg.forward_origin().is_some()
+}
+
+/// The caption of `msg` as HTML, keeping the targets of its links, when
+/// `preserve_links`, i.e. PRESERVE_CAPTION_LINKS, is set.
+fn caption_html(msg: &Message, preserve_links: bool) -> Option<String> {
+    msg.html_caption().filter(|_| preserve_links)
+}
+
+fn caption_matches(msg: &Message) -> bool {
+    match CAPTION_FILTER_REGEX.as_ref() {
+        Ok(Some(filter)) => caption_passes(msg.caption(), filter, *CAPTION_FILTER_INCLUDE_EMPTY),
+        _ => true,
+    }
+}
+
+fn caption_passes(caption: Option<&str>, filter: &regex::Regex, include_empty: bool) -> bool {
+    match caption {
+        Some(caption) => filter.is_match(caption),
+        None => include_empty,
+    }
+}
+
+/// Whether the text or caption of `msg` starts with a bot command. Commands in captions
+/// aren't picked up by the command branch, so they reach the default handler.
+fn is_command(msg: &Message) -> bool {
+    msg.text()
+        .or(msg.caption())
+        .is_some_and(|text| text.starts_with('/'))
+}
+
+/// Forum topic `msg` was posted in. Replies are sent there too so they don't land in General.
+pub fn topic_id(msg: &Message) -> Option<ThreadId> {
+    msg.is_topic_message.then_some(msg.thread_id).flatten()
+}
+
+async fn save_message(msg: &Message, dir: &Path, file_name: &String, archive: &ArchiveInfo) {
+    let extension = if *COMPRESS_METADATA {
+        ".json.gz"
+    } else {
+        ".json"
+    };
+    let path = dir.join(file_name.to_owned() + extension);
+    let msg_json = serde_json::to_value(msg)
+        .map(|mut value| {
+            if !archive.is_empty() {
+                value["archive"] = archive.clone().into();
+            }
+            for field in REDACT_FIELDS.iter() {
+                redact(&mut value, &field.split('.').collect::<Vec<_>>());
+            }
+            value
+        })
+        .and_then(|value| {
+            let json = sidecar_json(&value)?;
+            match *MAX_METADATA_BYTES {
+                Some(max) if json.len() > max => {
+                    log::warn!(
+                        "Metadata of {} is {} bytes, save a minimal version",
+                        file_name,
+                        json.len()
+                    );
+                    sidecar_json(&minimal_metadata(&value, json.len()))
+                }
+                _ => Ok(json),
+            }
+        })
+        .map_err(std::io::Error::other)
+        .and_then(|json| {
+            if *COMPRESS_METADATA {
+                gzip(json.as_bytes())
+            } else {
+                Ok(json.into_bytes())
+            }
+        });
+    let msg_file = create_file(&path).await;
+
+    match (&msg_file, &msg_json) {
+        (Ok(_), Ok(msg_json)) => {
+            let mut msg_file = msg_file.unwrap();
+            let mut written = msg_file.write_all(msg_json).await;
+            // tokio finishes the write in the background, and it would still be running
+            // when readers of the sidecar, like /zipme, look for it.
+            if written.is_ok() {
+                written = msg_file.flush().await;
+            }
+            let written = match written {
+                Ok(()) if *FSYNC_WRITES => sync_written(&msg_file, dir).await,
+                written => written,
+            };
+            if let Err(e) = written {
+                log::warn!("Save json {:?} error: {:?}", &path, e);
+            } else {
+                log::debug!("Save json {:?} successfully.", &path);
+            }
+        }
+        _ => {
+            log::warn!(
+                "Save message error: message: {:?}, file:{:?}",
+                &msg_file,
+                &msg_json
+            );
+        }
+    }
+}
+
+/// Sidecar paths checked for the media file at `path`, plain first.
+pub fn sidecar_paths(path: &Path) -> [PathBuf; 2] {
+    ["json", "json.gz"].map(|extension| {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(".");
+        sidecar.push(extension);
+        PathBuf::from(sidecar)
+    })
+}
+
+pub fn is_sidecar(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".json") || name.ends_with(".json.gz")
+}
+
+/// Reads the sidecar saved next to the media file at `path`, plain or gzipped.
+pub fn read_sidecar(path: &Path) -> Option<serde_json::Value> {
+    let [plain, compressed] = sidecar_paths(path);
+    let json = match std::fs::read(&plain) {
+        Ok(json) => json,
+        Err(_) => {
+            let mut json = Vec::new();
+            let file = std::fs::File::open(&compressed).ok()?;
+            std::io::Read::read_to_end(&mut GzDecoder::new(file), &mut json).ok()?;
+            json
+        }
+    };
+    serde_json::from_slice(&json)
+        .inspect_err(|e| log::warn!("Parse sidecar of {:?} error: {:?}", path, e))
+        .ok()
+}
+
+/// Flushes a newly written file and the directory entry pointing at it to disk.
+async fn sync_written(file: &File, dir: &Path) -> std::io::Result<()> {
+    file.sync_all().await?;
+    File::open(dir).await?.sync_all().await
+}
+
+/// Sidecar kept instead of message JSON over MAX_METADATA_BYTES: the ids, dates and
+/// media fields that identify the file, archive details and a note with the full size.
+fn minimal_metadata(value: &serde_json::Value, original_bytes: usize) -> serde_json::Value {
+    let mut minimal = serde_json::Map::new();
+    for key in [
+        "message_id",
+        "date",
+        "media_group_id",
+        "photo",
+        "video",
+        "archive",
+    ] {
+        if let Some(field) = value.get(key) {
+            minimal.insert(key.into(), field.clone());
+        }
+    }
+    minimal.insert(
+        "chat".into(),
+        serde_json::json!({ "id": value["chat"]["id"] }),
+    );
+    if let Some(id) = value["from"].get("id") {
+        minimal.insert("from".into(), serde_json::json!({ "id": id }));
+    }
+    minimal.insert(
+        "metadata_truncated".into(),
+        serde_json::json!({ "original_bytes": original_bytes }),
+    );
+    minimal.into()
+}
+
+/// Removes the field at `path` from `value`.
+pub fn redact(value: &mut serde_json::Value, path: &[&str]) {
+    match (value, path) {
+        (serde_json::Value::Array(items), _) => {
+            for item in items {
+                redact(item, path);
+            }
+        }
+        (serde_json::Value::Object(object), [key]) => {
+            object.remove(*key);
+        }
+        (serde_json::Value::Object(object), [key, rest @ ..]) => {
+            if let Some(child) = object.get_mut(*key) {
+                redact(child, rest);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn sidecar_json(value: &serde_json::Value) -> serde_json::Result<String> {
+    if *METADATA_PRETTY {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    }
+}
+
+/// Replaces the sidecar of the media file at `path`, keeping it plain or gzipped as it
+/// was. A new sidecar follows COMPRESS_METADATA.
+pub fn write_sidecar(path: &Path, value: &serde_json::Value) -> std::io::Result<()> {
+    let [plain, compressed] = sidecar_paths(path);
+    let json = sidecar_json(value)?;
+    if !plain.exists() && (compressed.exists() || *COMPRESS_METADATA) {
+        std::fs::write(&compressed, gzip(json.as_bytes())?)
+    } else {
+        std::fs::write(&plain, json)
+    }
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    std::io::Write::write_all(&mut encoder, data)?;
+    encoder.finish()
+}
+
+/// Current date in TIMEZONE, for day folders.
+pub fn today() -> NaiveDate {
+    date_in(Utc::now(), *TIMEZONE)
+}
+
+/// Date of `now` in `timezone`, or in local time without one.
+fn date_in(now: DateTime<Utc>, timezone: Option<chrono_tz::Tz>) -> NaiveDate {
+    match timezone {
+        Some(timezone) => now.with_timezone(&timezone).date_naive(),
+        None => now.with_timezone(&Local).date_naive(),
+    }
+}
+
+/// Reads a boolean switch from the environment, accepting `1`, `true` and `yes`.
+fn env_flag(key: &str) -> bool {
+    env::var(key)
+        .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+/// Creates `path` and its missing parents. Folders created meanwhile by another task
+/// are fine.
+pub fn ensure_dir_exists(path: &Path) -> Result<()> {
+    std::fs::create_dir_all(path)
+        .with_context(|| format!("Create download dir error: {}", path.display()))
+}
+
+/// Creates the file at `path`. Archive folders are only created here, when the first
+/// file goes into them, and created again if an operator cleared the archive while the
+/// bot runs. The file is retried once.
+async fn create_file(path: &Path) -> std::io::Result<File> {
+    match File::create(path).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let dir = path.parent().unwrap_or(Path::new("."));
+            log::debug!("Creating folder {:?}", dir);
+            ensure_dir_exists(dir).map_err(std::io::Error::other)?;
+            File::create(path).await
+        }
+        file => file,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// A text message from user 7 in supergroup -1001, with `fields` added on top. A
+    /// null field removes the default one.
+    fn message(fields: serde_json::Value) -> Message {
+        let mut value = serde_json::json!({
+            "message_id": 1,
+            "date": 1700000000,
+            "chat": { "id": -1001, "type": "supergroup", "title": "group" },
+            "from": { "id": 7, "is_bot": false, "first_name": "user", "username": "someone" },
+            "text": "hello",
+        });
+        let object = value.as_object_mut().unwrap();
+        for (key, field) in fields.as_object().unwrap() {
+            match field {
+                serde_json::Value::Null => object.remove(key),
+                field => object.insert(key.clone(), field.clone()),
+            };
+        }
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn general_topic_goes_to_general_folder() {
+        let forum = serde_json::json!({
+            "id": -1001, "type": "supergroup", "title": "forum", "is_forum": true
+        });
+        let day = PathBuf::from("day");
+
+        let general = message(serde_json::json!({ "chat": forum }));
+        assert_eq!(
+            grouped_dir(day.clone(), &Placement::of(&general), true, None),
+            day.join("general")
+        );
+
+        let topic = message(serde_json::json!({
+            "chat": forum, "message_thread_id": 5, "is_topic_message": true
+        }));
+        assert_eq!(
+            grouped_dir(day.clone(), &Placement::of(&topic), true, None),
+            day.join("topic_5")
+        );
+
+        let group = message(serde_json::json!({}));
+        assert_eq!(
+            grouped_dir(day.clone(), &Placement::of(&group), true, None),
+            day
+        );
+        assert_eq!(
+            grouped_dir(day.clone(), &Placement::of(&general), false, None),
+            day
+        );
+    }
+
+    #[test]
+    fn batches_reply_within_their_own_chat() {
+        let (chat_a, chat_b) = (ChatId(-1001), ChatId(-1002));
+        let mut state = ConsumerState::default();
+        state.push((chat_a, MessageId(1), None), None, String::from("a1"));
+        state.push((chat_b, MessageId(2), None), None, String::from("b2"));
+        state.push((chat_a, MessageId(3), None), None, String::from("a3"));
+
+        assert_eq!(state.batches.len(), 2);
+        let batch_a = &state.batches[&chat_a];
+        assert_eq!(batch_a.reply_to, MessageId(1));
+        assert_eq!(batch_a.statics, ["a1", "a3"]);
+        let batch_b = &state.batches[&chat_b];
+        assert_eq!(batch_b.reply_to, MessageId(2));
+        assert_eq!(batch_b.statics, ["b2"]);
+    }
+
+    #[test]
+    fn deferred_batch_goes_back_ahead_of_newer_statistics() {
+        let chat_id = ChatId(-1001);
+        let mut state = ConsumerState::default();
+        state.push((chat_id, MessageId(1), None), None, String::from("first"));
+        *state.pending.entry(ChatId(-1002)).or_default() += 1;
+        state.push(
+            (ChatId(-1002), MessageId(2), None),
+            None,
+            String::from("busy"),
+        );
+        assert!(state.take_ready(&HashSet::from([chat_id])).is_empty());
+        let ready = state.take_ready(&HashSet::new());
+        assert_eq!(ready.len(), 1);
+        assert!(state.batches.contains_key(&ChatId(-1002)));
+
+        // Recorded while the reply was out, then the reply was deferred.
+        let album = Some((String::from("album"), Some(MediaType::Photo)));
+        state.push((chat_id, MessageId(3), None), album.clone(), String::new());
+        let (_, batch) = ready.into_iter().next().unwrap();
+        state.put_back(chat_id, batch);
+        state.push((chat_id, MessageId(4), None), album, String::new());
+
+        let batch = &state.batches[&chat_id];
+        assert_eq!(batch.reply_to, MessageId(1));
+        assert_eq!(batch.statics, ["first", "相册: 2 项 (2 图)"]);
+    }
+
+    #[test]
+    fn slow_album_items_keep_the_summary_waiting() {
+        let (chat_id, other) = (ChatId(-1001), ChatId(-1002));
+        let quiet = Duration::from_secs(6);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut albums = HashMap::new();
+        albums.insert((chat_id, String::from("album")), at(0));
+        assert_eq!(
+            settling_chats(&mut albums, at(4), quiet),
+            HashSet::from([chat_id])
+        );
+
+        // The next item arrives after RECEIVE_TIMEOUT but within the quiet period.
+        albums.insert((chat_id, String::from("album")), at(5));
+        albums.insert((other, String::from("other")), at(5));
+        assert_eq!(settling_chats(&mut albums, at(10), quiet).len(), 2);
+        assert!(settling_chats(&mut albums, at(11), quiet).is_empty());
+        assert!(albums.is_empty());
+    }
+
+    #[test]
+    fn messages_over_max_inflight_are_rejected() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let first = Inflight::admit(&COUNTER, Some(2)).unwrap();
+        let _second = Inflight::admit(&COUNTER, Some(2)).unwrap();
+        assert_eq!(Inflight::admit(&COUNTER, Some(2)).err(), Some(2));
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 2);
+
+        // One download task finished.
+        drop(first);
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+        let _third = Inflight::admit(&COUNTER, Some(2)).unwrap();
+        assert!(Inflight::admit(&COUNTER, None).is_ok());
+    }
+
+    #[test]
+    fn summary_lines_cap_keeps_total() {
+        let statics: Vec<String> = (1..=5).map(|i| format!("item {}", i)).collect();
+        assert_eq!(summary_lines(&statics, None), statics);
+        assert_eq!(summary_lines(&statics, Some(5)), statics);
+        assert_eq!(
+            summary_lines(&statics, Some(2)),
+            ["共 5 项:", "item 1", "item 2", "... 以及 3 个更多"]
+        );
+    }
+
+    #[test]
+    fn sender_allowlist() {
+        let user = message(serde_json::json!({}));
+        let channel = message(serde_json::json!({
+            "sender_chat": { "id": -1002, "type": "channel", "title": "channel" }
+        }));
+
+        assert!(sender_in(&user, &[], false));
+        assert!(sender_in(&channel, &[], false));
+        assert!(sender_in(&user, &[UserId(7)], false));
+        assert!(!sender_in(&user, &[UserId(8)], true));
+        assert!(!sender_in(&channel, &[UserId(7)], false));
+        assert!(sender_in(&channel, &[UserId(7)], true));
+    }
+
+    #[test]
+    fn safe_file_name_truncates_long_names() {
+        let long = format!("{}.mp4", "视".repeat(100));
+        let name = safe_file_name(&long);
+        assert!(name.len() <= MAX_FILE_NAME_BYTES);
+        assert!(name.starts_with("视视"));
+        assert!(name.ends_with(".mp4"));
+        assert_eq!(name, safe_file_name(&long));
+
+        let other = format!("{}.mp4", "视".repeat(101));
+        assert_ne!(safe_file_name(&other), name);
+
+        let ascii = safe_file_name(&"a".repeat(300));
+        assert_eq!(ascii.len(), MAX_FILE_NAME_BYTES);
+        assert!(ascii.starts_with("aaa"));
+    }
+
+    #[test]
+    fn safe_file_name_stays_in_folder() {
+        assert_eq!(safe_file_name("../../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(safe_file_name("a\nb.mp4"), "a_b.mp4");
+        assert_eq!(safe_file_name(".."), "unnamed");
+        assert_eq!(safe_file_name("clip.mp4"), "clip.mp4");
+    }
+
+    #[test]
+    fn command_text_and_captions() {
+        let text = |text: &str| message(serde_json::json!({ "text": text }));
+        assert!(is_command(&text("/get x")));
+        assert!(!is_command(&text("a /get")));
+
+        let photo = serde_json::json!([{
+            "file_id": "f", "file_unique_id": "u", "width": 1, "height": 1, "file_size": 1
+        }]);
+        let captioned = |caption: &str| {
+            message(serde_json::json!({ "text": null, "photo": photo, "caption": caption }))
+        };
+        assert!(is_command(&captioned("/zipme")));
+        assert!(!is_command(&captioned("holiday")));
+    }
+
+    #[test]
+    fn sniff_extension_from_magic_bytes() {
+        let riff = |kind: &[u8]| [b"RIFF\0\0\0\0".as_slice(), kind].concat();
+        let ftyp = |brand: &[u8]| [b"\0\0\0\x18ftyp".as_slice(), brand].concat();
+        let ebml =
+            |doc_type: &[u8]| [b"\x1a\x45\xdf\xa3\x9f\x42\x82\x84".as_slice(), doc_type].concat();
+
+        assert_eq!(sniff_extension(b"\xff\xd8\xff\xe0"), Some("jpg"));
+        assert_eq!(sniff_extension(b"\x89PNG\r\n"), Some("png"));
+        assert_eq!(sniff_extension(b"GIF89a"), Some("gif"));
+        assert_eq!(sniff_extension(&riff(b"WEBP")), Some("webp"));
+        assert_eq!(sniff_extension(&riff(b"AVI ")), Some("avi"));
+        assert_eq!(sniff_extension(&ftyp(b"qt  ")), Some("mov"));
+        assert_eq!(sniff_extension(&ftyp(b"isom")), Some("mp4"));
+        assert_eq!(sniff_extension(&ftyp(b"3gp4")), Some("3gp"));
+        assert_eq!(sniff_extension(&ftyp(b"3g2a")), Some("3g2"));
+        assert_eq!(sniff_extension(&ftyp(b"M4V ")), Some("m4v"));
+        assert_eq!(sniff_extension(&ebml(b"webm")), Some("webm"));
+        assert_eq!(sniff_extension(&ebml(b"matroska")), Some("mkv"));
+        assert_eq!(sniff_extension(b"plain text"), None);
+        assert_eq!(sniff_extension(b""), None);
+    }
+
+    #[test]
+    fn redact_removes_nested_fields() {
+        let mut value = serde_json::json!({
+            "from": { "id": 7, "username": "someone" },
+            "photo": [{ "file_id": "a", "width": 1 }, { "file_id": "b", "width": 2 }],
+            "text": "hello",
+        });
+        redact(&mut value, &["from", "username"]);
+        redact(&mut value, &["photo", "file_id"]);
+        redact(&mut value, &["text"]);
+        redact(&mut value, &["missing", "field"]);
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "from": { "id": 7 },
+                "photo": [{ "width": 1 }, { "width": 2 }],
+            })
+        );
+    }
+
+    #[test]
+    fn day_folder_date_follows_timezone() {
+        // 2024-01-01 20:30 UTC is already the next day in Shanghai, still the same
+        // day in New York.
+        let now = DateTime::from_timestamp(1704141000, 0).unwrap();
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        assert_eq!(date_in(now, Some(chrono_tz::UTC)), date(2024, 1, 1));
+        assert_eq!(
+            date_in(now, Some(chrono_tz::Asia::Shanghai)),
+            date(2024, 1, 2)
+        );
+        assert_eq!(
+            date_in(now, Some(chrono_tz::America::New_York)),
+            date(2024, 1, 1)
+        );
+    }
+
+    #[test]
+    fn minimal_metadata_keeps_identifying_fields() {
+        let value = serde_json::json!({
+            "message_id": 1,
+            "date": 1700000000,
+            "chat": { "id": -1001, "title": "group" },
+            "from": { "id": 7, "username": "someone" },
+            "video": { "file_id": "f" },
+            "caption": "a long caption",
+            "archive": { "file": "a.mp4" },
+        });
+        assert_eq!(
+            minimal_metadata(&value, 4096),
+            serde_json::json!({
+                "message_id": 1,
+                "date": 1700000000,
+                "chat": { "id": -1001 },
+                "from": { "id": 7 },
+                "video": { "file_id": "f" },
+                "archive": { "file": "a.mp4" },
+                "metadata_truncated": { "original_bytes": 4096 },
+            })
+        );
+
+        let anonymous = serde_json::json!({ "message_id": 2, "chat": { "id": -1001 } });
+        assert!(minimal_metadata(&anonymous, 1).get("from").is_none());
+    }
+
+    #[test]
+    fn caption_filter() {
+        let filter = regex::Regex::new("(?i)#keep").unwrap();
+        assert!(caption_passes(Some("trip #Keep"), &filter, false));
+        assert!(!caption_passes(Some("trip"), &filter, true));
+        assert!(!caption_passes(None, &filter, false));
+        assert!(caption_passes(None, &filter, true));
+    }
+
+    #[test]
+    fn retry_delay_doubles() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(500),
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(500));
+        assert_eq!(policy.delay(2), Duration::from_millis(1000));
+        assert_eq!(policy.delay(4), Duration::from_millis(4000));
+        assert_eq!(policy.delay(0), Duration::from_millis(500));
+        assert_eq!(policy.delay(100), Duration::from_millis(500) * u32::MAX);
+    }
+
+    #[test]
+    fn album_lines_in_both_modes() {
+        let chat_id = ChatId(-1001);
+        let msg = |id| (chat_id, MessageId(id), None);
+
+        let mut listed = ConsumerState::default();
+        listed.push(msg(1), None, String::from("photo 1"));
+        listed.push(msg(2), None, String::from("video 2"));
+        assert_eq!(listed.batches[&chat_id].statics, ["photo 1", "video 2"]);
+
+        let mut compact = ConsumerState::default();
+        let item = |media_type| Some((String::from("album"), Some(media_type)));
+        compact.push(msg(1), None, String::from("text"));
+        compact.push(msg(2), item(MediaType::Photo), String::new());
+        compact.push(msg(3), item(MediaType::Photo), String::new());
+        compact.push(msg(4), item(MediaType::Video), String::new());
+        compact.push(msg(5), None, String::from("after"));
+        assert_eq!(
+            compact.batches[&chat_id].statics,
+            ["text", "相册: 3 项 (2 图 1 视频)", "after"]
+        );
+    }
+
+    #[test]
+    fn album_line_counts_failures() {
+        let line = |photos, videos, failed| {
+            AlbumLine {
+                index: 0,
+                photos,
+                videos,
+                failed,
+            }
+            .line()
+        };
+        assert_eq!(line(2, 1, 0), "相册: 3 项 (2 图 1 视频)");
+        assert_eq!(line(2, 0, 1), "相册: 2 成功 1 失败 (2 图)");
+        assert_eq!(line(0, 0, 3), "相册: 0 成功 3 失败");
+
+        let chat_id = ChatId(-1001);
+        let mut state = ConsumerState::default();
+        let item = |media_type| Some((String::from("album"), media_type));
+        state.push(
+            (chat_id, MessageId(1), None),
+            item(Some(MediaType::Video)),
+            String::new(),
+        );
+        state.push((chat_id, MessageId(2), None), item(None), String::new());
+        assert_eq!(
+            state.batches[&chat_id].statics,
+            ["相册: 1 成功 1 失败 (1 视频)"]
+        );
+    }
+
+    #[test]
+    fn mime_extension_for_known_types() {
+        assert_eq!(mime_extension("image/jpeg"), Some("jpg"));
+        assert_eq!(mime_extension("video/quicktime"), Some("mov"));
+        assert_eq!(mime_extension("video/3gpp"), Some("3gp"));
+        assert_eq!(mime_extension("application/octet-stream"), None);
+        assert_eq!(mime_extension(""), None);
+    }
+
+    #[test]
+    fn shared_file_gets_a_name_per_message() {
+        let video = |message_id, file_name: Option<&str>| {
+            message(serde_json::json!({
+                "message_id": message_id,
+                "text": null,
+                "video": {
+                    "file_id": "shared", "file_unique_id": "u1", "file_size": 1,
+                    "width": 1, "height": 1, "duration": 1, "file_name": file_name,
+                    "mime_type": "video/mp4",
+                },
+            }))
+        };
+        let name = |msg: &Message| {
+            let video = msg.video().unwrap();
+            match video.file_name.as_deref() {
+                Some(name) => sender_file_name(name, &video.file, msg.id, "mp4"),
+                None => media_file_name("video", &video.file, msg.id, "mp4"),
+            }
+        };
+        let (first, second) = (video(1, Some("video.mp4")), video(2, Some("video.mp4")));
+        assert_eq!(name(&first), "video_u1_1.mp4");
+        assert_eq!(name(&second), "video_u1_2.mp4");
+        assert_eq!(name(&video(3, Some("clip"))), "clip_u1_3.mp4");
+        assert_eq!(name(&video(4, None)), "video_u1_4.mp4");
+        assert_ne!(name(&video(5, None)), name(&video(6, None)));
+    }
+
+    #[test]
+    fn ftyp_family_extensions_match() {
+        assert!(same_media_type("jpeg", "jpg"));
+        assert!(same_media_type("3gp", "mp4"));
+        assert!(same_media_type("m4v", "mp4"));
+        assert!(same_media_type("mp4", "mov"));
+        assert!(!same_media_type("jpg", "png"));
+        assert!(!same_media_type("mkv", "mp4"));
+    }
+
+    #[test]
+    fn unsupported_media_kinds() {
+        let sticker = message(serde_json::json!({
+            "text": null,
+            "sticker": {
+                "file_id": "f", "file_unique_id": "u", "file_size": 1, "width": 1, "height": 1,
+                "is_animated": false, "is_video": false, "type": "regular",
+            },
+        }));
+        assert_eq!(unsupported_media(&sticker.kind), Some("sticker"));
+
+        // An attachment type teloxide doesn't know yet.
+        let unknown = message(serde_json::json!({
+            "text": null,
+            "paid_media": { "star_count": 5, "paid_media": [{ "type": "preview" }] },
+        }));
+        assert_eq!(unsupported_media(&unknown.kind), Some("unknown"));
+
+        assert_eq!(
+            unsupported_media(&message(serde_json::json!({})).kind),
+            None
+        );
+        let service = message(serde_json::json!({ "text": null, "new_chat_title": "new" }));
+        assert_eq!(unsupported_media(&service.kind), None);
+    }
+
+    /// A FileSource answering from queued results, and panicking when there is none.
+    #[derive(Default)]
+    struct FakeSource {
+        files: std::sync::Mutex<VecDeque<Result<TgFile, RequestError>>>,
+        bodies: std::sync::Mutex<VecDeque<Result<&'static [u8], TransferError>>>,
+        /// Paths download_file was asked for.
+        fetched: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl FakeSource {
+        fn new(
+            files: impl IntoIterator<Item = Result<TgFile, RequestError>>,
+            bodies: impl IntoIterator<Item = Result<&'static [u8], TransferError>>,
+        ) -> Self {
+            FakeSource {
+                files: std::sync::Mutex::new(files.into_iter().collect()),
+                bodies: std::sync::Mutex::new(bodies.into_iter().collect()),
+                fetched: Default::default(),
+            }
+        }
+
+        fn fetched(&self) -> Vec<String> {
+            self.fetched.lock().unwrap().clone()
+        }
+    }
+
+    impl FileSource for FakeSource {
+        async fn get_file(&self, _: &str) -> Result<TgFile, RequestError> {
+            let file = self.files.lock().unwrap().pop_front();
+            file.expect("unexpected get_file")
+        }
+
+        async fn download_file(
+            &self,
+            file_path: &str,
+            dst: &mut (dyn AsyncWrite + Unpin + Send),
+        ) -> Result<(), TransferError> {
+            self.fetched.lock().unwrap().push(file_path.to_string());
+            let body = self.bodies.lock().unwrap().pop_front();
+            let body = body.expect("unexpected download_file")?;
+            dst.write_all(body).await.map_err(anyhow::Error::from)?;
+            Ok(())
+        }
+    }
+
+    /// File info as get_file returns it, with `path` to download from.
+    fn tg_file(path: &str, size: u32) -> TgFile {
+        serde_json::from_value(serde_json::json!({
+            "file_id": "f", "file_unique_id": "u", "file_size": size, "file_path": path
+        }))
+        .unwrap()
+    }
+
+    // Paused time skips the pauses between retries.
+    #[tokio::test(start_paused = true)]
+    async fn truncated_download_is_retried() {
+        let dir = testing::TempDir::new("truncated");
+        let path = dir.join("photo_u_1.jpg");
+        let source = FakeSource::new([], [Ok(b"abc".as_slice()), Ok(b"abcde".as_slice())]);
+
+        download_to(&source, "f", tg_file("photos/1.jpg", 5), &path)
+            .await
+            .unwrap();
+        assert_eq!(source.fetched().len(), 2);
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcde");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn empty_download_is_retried_then_removed() {
+        let dir = testing::TempDir::new("empty");
+        let path = dir.join("photo_u_1.jpg");
+        let source = FakeSource::new([], [Ok(b"".as_slice()), Ok(b"".as_slice())]);
+
+        let error = download_to(&source, "f", tg_file("photos/1.jpg", 5), &path)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<DownloadError>(),
+            Some(DownloadError::Empty)
+        ));
+        assert_eq!(source.fetched().len(), 2);
+        assert!(!path.exists());
+
+        let source = FakeSource::new([], [Ok(b"".as_slice()), Ok(b"abcde".as_slice())]);
+        download_to(&source, "f", tg_file("photos/1.jpg", 5), &path)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcde");
+    }
+
+    fn network_error(status: u16) -> TransferError {
+        TransferError::Network {
+            error: anyhow::anyhow!("status {}", status),
+            status: Some(status),
+        }
+    }
+
+    fn connection_reset() -> RequestError {
+        RequestError::Io(Arc::new(std::io::Error::other("connection reset")))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn download_retry_refreshes_expired_paths() {
+        let dir = testing::TempDir::new("expired");
+        let path = dir.join("photo_u_1.jpg");
+        let abcde = || Ok(b"abcde".as_slice());
+
+        let files = [Ok(tg_file("old", 5)), Ok(tg_file("new", 5))];
+        let source = FakeSource::new(files, [Err(network_error(404)), abcde()]);
+        transfer(&source, "f", &path, false).await.unwrap();
+        assert_eq!(source.fetched(), ["old", "new"]);
+
+        // A server error is retried on the same path.
+        let source = FakeSource::new([Ok(tg_file("old", 5))], [Err(network_error(502)), abcde()]);
+        transfer(&source, "f", &path, false).await.unwrap();
+        assert_eq!(source.fetched(), ["old", "old"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn download_retry_gives_up_after_the_last_attempt() {
+        let dir = testing::TempDir::new("give_up");
+        let path = dir.join("photo_u_1.jpg");
+        let attempts = DOWNLOAD_RETRY.attempts as usize;
+        let errors = (0..attempts).map(|_| Err(network_error(502)));
+        let source = FakeSource::new([], errors);
+
+        let error = download_to(&source, "f", tg_file("old", 5), &path)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<TransferError>().is_some());
+        assert_eq!(source.fetched().len(), attempts);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn get_file_retry_path() {
+        let source = FakeSource::new([Err(connection_reset()), Ok(tg_file("p", 5))], []);
+        assert_eq!(get_file(&source, "f").await.unwrap().path, "p");
+
+        let attempts = GET_FILE_RETRY.attempts as usize;
+        let source = FakeSource::new((0..attempts).map(|_| Err(connection_reset())), []);
+        assert!(get_file(&source, "f").await.is_err());
+        assert!(source.files.lock().unwrap().is_empty());
+
+        // An invalid file id won't change on retry.
+        let invalid = RequestError::Api(ApiError::FileIdInvalid);
+        let source = FakeSource::new([Err(invalid), Ok(tg_file("p", 5))], []);
+        assert!(get_file(&source, "f").await.is_err());
+        assert_eq!(source.files.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn file_without_path_is_not_downloaded() {
+        assert!(matches!(
+            with_download_path(tg_file("", 5)),
+            Err(DownloadError::TooBig)
+        ));
+        assert!(with_download_path(tg_file("videos/1.mp4", 5)).is_ok());
+
+        let dir = testing::TempDir::new("no_path");
+        let path = dir.join("video_u_1.mp4");
+        let source = FakeSource::new([Ok(tg_file("", 5))], []);
+        let error = transfer(&source, "f", &path, false).await.unwrap_err();
+        assert_eq!(error.to_string(), "文件不可下载（过大）");
+        assert!(source.fetched().is_empty());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn create_file_recreates_a_removed_archive() {
+        let root = testing::TempDir::new("removed");
+        create_file(&root.join("2024-01-01").join("photo_u_1.jpg"))
+            .await
+            .unwrap();
+
+        // An operator clears the archive while the bot runs.
+        std::fs::remove_dir_all(&*root).unwrap();
+        let path = root.join("2024-01-01").join("photo_u_2.jpg");
+        let mut file = create_file(&path).await.unwrap();
+        file.write_all(b"b").await.unwrap();
+        file.flush().await.unwrap();
+        assert!(root.join("2024-01-01").is_dir());
+        assert_eq!(std::fs::read(&path).unwrap(), b"b");
+    }
+
+    #[tokio::test]
+    async fn caption_links_are_saved_in_the_sidecar() {
+        let photo = serde_json::json!([{
+            "file_id": "f", "file_unique_id": "u", "width": 1, "height": 1, "file_size": 1
+        }]);
+        let msg = message(serde_json::json!({
+            "text": null,
+            "photo": photo,
+            "caption": "see the docs by @someone",
+            "caption_entities": [
+                { "type": "text_link", "offset": 4, "length": 8, "url": "https://example.com/" },
+                { "type": "bold", "offset": 8, "length": 4 },
+                { "type": "mention", "offset": 16, "length": 8 },
+            ],
+        }));
+        assert_eq!(caption_html(&msg, false), None);
+
+        let dir = testing::TempDir::new("caption_html");
+        let mut archive = ArchiveInfo::new();
+        archive.insert("caption_html".into(), caption_html(&msg, true).into());
+        save_message(&msg, &dir, &String::from("photo_u_1.jpg"), &archive).await;
+        let sidecar = read_sidecar(&dir.join("photo_u_1.jpg")).unwrap();
+        assert_eq!(
+            sidecar["archive"]["caption_html"],
+            "see <a href=\"https://example.com/\">the <b>docs</b></a> by @someone"
+        );
+        assert_eq!(
+            sidecar["caption_entities"][0]["url"],
+            "https://example.com/"
+        );
+    }
+
+    #[tokio::test]
+    async fn forwarded_message_keeps_its_origin() {
+        let forwarded = message(serde_json::json!({
+            "forward_origin": {
+                "type": "user",
+                "date": 1690000000,
+                "sender_user": { "id": 9, "is_bot": false, "first_name": "origin" },
+            },
+        }));
+        let original = message(serde_json::json!({}));
+        assert!(skips_forward(&forwarded, true));
+        assert!(!skips_forward(&forwarded, false));
+        assert!(!skips_forward(&original, true));
+
+        let dir = testing::TempDir::new("forward");
+        let name = String::from("photo_u_1.jpg");
+        save_message(&forwarded, &dir, &name, &ArchiveInfo::new()).await;
+        let sidecar = read_sidecar(&dir.join(&name)).unwrap();
+        assert_eq!(sidecar["forward_origin"]["type"], "user");
+        assert_eq!(sidecar["forward_origin"]["date"], 1690000000);
+        assert_eq!(sidecar["forward_origin"]["sender_user"]["id"], 9);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn duplicate_message_is_downloaded_once() {
+        let msg = (ChatId(-1001), MessageId(287));
+        let barrier = Arc::new(tokio::sync::Barrier::new(2));
+        let tasks = (0..2).map(|_| {
+            let barrier = barrier.clone();
+            tokio::spawn(async move {
+                barrier.wait().await;
+                let claim = Claim::new(msg);
+                // Hold the claim like a running download until both tasks tried.
+                barrier.wait().await;
+                claim.is_some()
+            })
+        });
+        let mut won = 0;
+        for task in tasks.collect::<Vec<_>>() {
+            won += task.await.unwrap() as usize;
+        }
+        assert_eq!(won, 1);
+        assert!(Claim::new(msg).is_some());
     }
-    Ok(())
 }
@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use teloxide::{prelude::*, types::ChatMemberUpdated};
+
+use crate::{
+    ADMIN_CHAT_ID,
+    config::{self, RUNTIME_CONFIG},
+};
+
+/// Tell ADMIN_CHAT_ID when the bot is added to or removed from a chat.
+pub static MEMBERSHIP_NOTIFY: Lazy<bool> = Lazy::new(|| crate::env_flag("MEMBERSHIP_NOTIFY"));
+/// Pause archiving a chat when the bot is removed from it, until it is added again or
+/// an admin sends /allow for it.
+pub static MEMBERSHIP_PAUSE_REMOVED: Lazy<bool> =
+    Lazy::new(|| crate::env_flag("MEMBERSHIP_PAUSE_REMOVED"));
+
+/// Handles `my_chat_member` updates, which report the bot joining or leaving a chat.
+pub async fn handle_update(bot: Arc<Bot>, update: ChatMemberUpdated) -> ResponseResult<()> {
+    let (was_present, is_present) = (
+        update.old_chat_member.is_present(),
+        update.new_chat_member.is_present(),
+    );
+    if was_present == is_present {
+        return Ok(());
+    }
+    let chat_id = update.chat.id;
+    let title = update.chat.title().unwrap_or_default().to_owned();
+    log::info!(
+        "Bot {} chat {} {:?} by {}",
+        if is_present {
+            "added to"
+        } else {
+            "removed from"
+        },
+        chat_id,
+        title,
+        update.from.id
+    );
+
+    let mut config = RUNTIME_CONFIG.write().await;
+    let paused_changed = config.set_member(chat_id.0, is_present, *MEMBERSHIP_PAUSE_REMOVED);
+    if paused_changed && let Err(e) = config::save(&config).await {
+        log::warn!("{:?}", e);
+    }
+    drop(config);
+
+    if *MEMBERSHIP_NOTIFY && let Some(admin_chat_id) = *ADMIN_CHAT_ID {
+        let text = if is_present {
+            format!("已加入聊天 {} ({})", title, chat_id)
+        } else if *MEMBERSHIP_PAUSE_REMOVED {
+            format!("已被移出聊天 {} ({})，已暂停该聊天的存档", title, chat_id)
+        } else {
+            format!("已被移出聊天 {} ({})", title, chat_id)
+        };
+        bot.send_message(admin_chat_id, text).await?;
+    }
+    Ok(())
+}
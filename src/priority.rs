@@ -0,0 +1,88 @@
+use std::env;
+
+use once_cell::sync::Lazy;
+
+/// Nice level for the bot, e.g. `10`, so archiving yields the CPU to other services.
+static PROCESS_NICE: Lazy<Option<i32>> = Lazy::new(|| {
+    env::var("PROCESS_NICE")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+});
+/// Put the bot in the idle IO scheduling class, so its disk writes wait for other
+/// processes. Linux only.
+static IO_IDLE: Lazy<bool> = Lazy::new(|| crate::env_flag("IO_IDLE"));
+
+/// Effective priority settings for /config.
+pub fn settings() -> Vec<(&'static str, String)> {
+    vec![
+        ("PROCESS_NICE", format!("{:?}", *PROCESS_NICE)),
+        ("IO_IDLE", IO_IDLE.to_string()),
+    ]
+}
+
+/// Applies PROCESS_NICE and IO_IDLE.
+///
+/// Linux keeps both per thread, so they are set on every thread that already runs,
+/// including the runtime workers; threads started later inherit them. Other unix
+/// systems only support the nice level, and elsewhere both are ignored with a warning.
+pub fn apply() {
+    if PROCESS_NICE.is_none() && !*IO_IDLE {
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let threads = std::fs::read_dir("/proc/self/task")
+            .map(|tasks| {
+                tasks
+                    .flatten()
+                    .filter_map(|task| task.file_name().to_str()?.parse::<libc::id_t>().ok())
+                    .collect()
+            })
+            .unwrap_or_else(|_| vec![0]);
+        for thread in threads {
+            set_priority(thread);
+        }
+    }
+    #[cfg(all(unix, not(target_os = "linux")))]
+    {
+        if *IO_IDLE {
+            log::warn!("IO_IDLE is only supported on Linux, skip it");
+        }
+        set_priority(0);
+    }
+    #[cfg(not(unix))]
+    log::warn!("PROCESS_NICE and IO_IDLE are only supported on unix, skip them");
+}
+
+#[cfg(unix)]
+fn set_priority(thread: libc::id_t) {
+    if let Some(nice) = *PROCESS_NICE
+        && unsafe { libc::setpriority(libc::PRIO_PROCESS, thread, nice) } != 0
+    {
+        log::warn!(
+            "Set nice level {} error: {}",
+            nice,
+            std::io::Error::last_os_error()
+        );
+    }
+    #[cfg(target_os = "linux")]
+    if *IO_IDLE {
+        const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                thread as libc::c_long,
+                IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            )
+        };
+        if result != 0 {
+            log::warn!(
+                "Set idle IO priority error: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
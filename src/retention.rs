@@ -0,0 +1,233 @@
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{Days, NaiveDate};
+use once_cell::sync::Lazy;
+
+use crate::{
+    commands::dir_size, config::MediaType, is_sidecar, read_sidecar, sidecar_paths,
+    thumbnail::thumbnail_path,
+};
+
+/// Days a day folder is kept, used by /cleanup when no threshold is given.
+pub static RETENTION_DAYS: Lazy<Option<u64>> = Lazy::new(|| days_from_env("RETENTION_DAYS"));
+/// Days photos are kept, overriding RETENTION_DAYS.
+pub static RETENTION_PHOTO_DAYS: Lazy<Option<u64>> =
+    Lazy::new(|| days_from_env("RETENTION_PHOTO_DAYS"));
+/// Days videos are kept, overriding RETENTION_DAYS.
+pub static RETENTION_VIDEO_DAYS: Lazy<Option<u64>> =
+    Lazy::new(|| days_from_env("RETENTION_VIDEO_DAYS"));
+
+fn days_from_env(key: &str) -> Option<u64> {
+    let days = env::var(key).ok()?;
+    days.trim()
+        .parse()
+        .inspect_err(|e| log::warn!("Invalid {} {:?}: {:?}", key, days, e))
+        .ok()
+}
+
+/// Days files of `media_type` are kept. Files that aren't photos or videos, like saved
+/// service messages, use RETENTION_DAYS.
+fn retention_days(media_type: Option<MediaType>) -> Option<u64> {
+    period_for(
+        media_type,
+        *RETENTION_DAYS,
+        *RETENTION_PHOTO_DAYS,
+        *RETENTION_VIDEO_DAYS,
+    )
+}
+
+fn period_for(
+    media_type: Option<MediaType>,
+    days: Option<u64>,
+    photo_days: Option<u64>,
+    video_days: Option<u64>,
+) -> Option<u64> {
+    match media_type {
+        Some(MediaType::Photo) => photo_days.or(days),
+        Some(MediaType::Video) => video_days.or(days),
+        None => days,
+    }
+}
+
+/// Whether any retention period is configured.
+pub fn configured() -> bool {
+    RETENTION_DAYS.is_some() || RETENTION_PHOTO_DAYS.is_some() || RETENTION_VIDEO_DAYS.is_some()
+}
+
+/// Archived items that are past their retention period.
+#[derive(Default)]
+pub struct Expired {
+    /// Whole day folders, or single archived files when per-type periods apply.
+    pub items: usize,
+    /// Every path to delete, including sidecars, originals and thumbnails.
+    pub paths: Vec<PathBuf>,
+    pub bytes: u64,
+    /// The download roots searched, which are kept even when left empty.
+    roots: Vec<PathBuf>,
+}
+
+/// Finds what is past retention in the `%Y-%m-%d` folders of the download `roots`.
+/// Other entries, like the config file, are never touched.
+///
+/// With `days` set, or when no per-type period is configured, whole day folders older
+/// than the threshold expire. Otherwise each archived file is checked against the
+/// period of its media type, read from its sidecar.
+pub fn expired(roots: Vec<PathBuf>, days: Option<u64>) -> io::Result<Expired> {
+    let per_type =
+        days.is_none() && (RETENTION_PHOTO_DAYS.is_some() || RETENTION_VIDEO_DAYS.is_some());
+    let today = crate::today();
+    let is_expired = |date: NaiveDate, days: Option<u64>| {
+        days.is_some_and(|days| date < today.checked_sub_days(Days::new(days)).unwrap_or(today))
+    };
+
+    let mut expired = Expired::default();
+    for (date, dir) in day_folders(&roots)? {
+        if !per_type {
+            if is_expired(date, days.or(*RETENTION_DAYS)) {
+                expired.items += 1;
+                expired.bytes += dir_size(&dir)?;
+                expired.paths.push(dir);
+            }
+            continue;
+        }
+        for (path, media_type) in archived_files(&dir)? {
+            if !is_expired(date, retention_days(media_type)) {
+                continue;
+            }
+            expired.items += 1;
+            for path in related_paths(&path) {
+                if let Ok(metadata) = std::fs::metadata(&path) {
+                    expired.bytes += metadata.len();
+                    expired.paths.push(path);
+                }
+            }
+        }
+    }
+    expired.roots = roots;
+    Ok(expired)
+}
+
+fn day_folders(roots: &[PathBuf]) -> io::Result<Vec<(NaiveDate, PathBuf)>> {
+    let mut folders = Vec::new();
+    for root in roots {
+        let entries = match std::fs::read_dir(root) {
+            // Per-chat folders only exist once something was archived there.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            entries => entries?,
+        };
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let name = entry.file_name();
+            if let Ok(date) = NaiveDate::parse_from_str(&name.to_string_lossy(), "%Y-%m-%d") {
+                folders.push((date, entry.path()));
+            }
+        }
+    }
+    folders.sort();
+    Ok(folders)
+}
+
+/// Archived files under `dir` with their media type. Sidecars without a media file
+/// next to them, i.e. saved service messages, are listed themselves with no type.
+fn archived_files(dir: &Path) -> io::Result<Vec<(PathBuf, Option<MediaType>)>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if path.file_name().is_some_and(|name| name != "thumbnails") {
+                files.extend(archived_files(&path)?);
+            }
+            continue;
+        }
+        if is_sidecar(&path) {
+            let name = path.to_string_lossy();
+            let media = name
+                .strip_suffix(".json.gz")
+                .or(name.strip_suffix(".json"))
+                .map(PathBuf::from);
+            if media.is_some_and(|media| !media.exists()) {
+                files.push((path, None));
+            }
+            continue;
+        }
+        let Some(sidecar) = read_sidecar(&path) else {
+            continue;
+        };
+        let media_type = if sidecar.get("photo").is_some() {
+            Some(MediaType::Photo)
+        } else if sidecar.get("video").is_some() {
+            Some(MediaType::Video)
+        } else {
+            None
+        };
+        files.push((path, media_type));
+    }
+    Ok(files)
+}
+
+/// The archived file at `path` and everything saved alongside it: sidecars, the kept
+/// original and the thumbnail.
+pub fn related_paths(path: &Path) -> Vec<PathBuf> {
+    if is_sidecar(path) {
+        return vec![path.to_path_buf()];
+    }
+    let mut paths = vec![path.to_path_buf(), thumbnail_path(path)];
+    if let Some(original) = read_sidecar(path)
+        .as_ref()
+        .and_then(|sidecar| sidecar["archive"]["original"].as_str())
+    {
+        paths.push(path.with_file_name(original));
+    }
+    paths.extend(sidecar_paths(path));
+    paths
+}
+
+/// Deletes the expired paths and returns how many bytes were freed. Folders left empty
+/// are removed too.
+pub fn remove(expired: &Expired) -> io::Result<u64> {
+    let mut freed = 0;
+    for path in &expired.paths {
+        let bytes = dir_size(path)?;
+        if path.is_dir() {
+            std::fs::remove_dir_all(path)?;
+        } else {
+            std::fs::remove_file(path)?;
+        }
+        log::info!("Removed expired archive {:?}", path);
+        freed += bytes;
+        remove_empty_parents(path, &expired.roots);
+    }
+    Ok(freed)
+}
+
+fn remove_empty_parents(path: &Path, roots: &[PathBuf]) {
+    let mut dir = path.parent();
+    while let Some(parent) = dir {
+        if roots.iter().any(|root| root == parent) || std::fs::remove_dir(parent).is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_type_period_overrides_default() {
+        let photo = Some(MediaType::Photo);
+        let video = Some(MediaType::Video);
+        assert_eq!(period_for(photo, Some(30), Some(7), None), Some(7));
+        assert_eq!(period_for(video, Some(30), Some(7), None), Some(30));
+        assert_eq!(period_for(None, Some(30), Some(7), Some(3)), Some(30));
+        assert_eq!(period_for(video, None, Some(7), Some(3)), Some(3));
+        assert_eq!(period_for(video, None, Some(7), None), None);
+    }
+}
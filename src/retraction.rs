@@ -0,0 +1,275 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use teloxide::{
+    types::{ChatId, Message, MessageId},
+    utils::render::RenderMessageTextHelper,
+};
+
+use crate::{
+    PRESERVE_CAPTION_LINKS, REDACT_FIELDS, TIMEZONE, config::RUNTIME_CONFIG, is_sidecar,
+    read_sidecar, redact, write_sidecar,
+};
+
+/// Mark archived media as retracted when its message is edited to no longer carry it.
+/// Archives are left untouched when unset.
+pub static HONOR_RETRACTIONS: Lazy<bool> = Lazy::new(|| crate::env_flag("HONOR_RETRACTIONS"));
+/// Refresh the caption saved in the sidecar when a message is edited but keeps its
+/// media. The file itself is never downloaded again.
+pub static UPDATE_EDITED_CAPTIONS: Lazy<bool> =
+    Lazy::new(|| crate::env_flag("UPDATE_EDITED_CAPTIONS"));
+
+/// Message fields an edit can change without replacing the media.
+const CAPTION_FIELDS: [&str; 4] = [
+    "caption",
+    "caption_entities",
+    "show_caption_above_media",
+    "edit_date",
+];
+
+enum Edit {
+    /// The media was removed, at the edit time.
+    Retracted(Option<String>),
+    /// The media with `unique_id` was kept, and `fields` are its new caption fields.
+    Caption {
+        unique_id: String,
+        fields: serde_json::Value,
+        caption_html: Option<String>,
+    },
+}
+
+/// Handles an `edited_message` update. Edited messages are never downloaded again.
+///
+/// When the edit removed the media, the archived copy stays in place and its sidecar
+/// gets `archive.retracted` with the edit time. When it kept the same media, only the
+/// caption in the sidecar is updated. Media replaced by the edit is left as archived.
+pub async fn handle_edit(msg: Message) {
+    let unique_id = msg
+        .photo()
+        .and_then(|sizes| sizes.last())
+        .map(|photo| &photo.file.unique_id)
+        .or(msg.video().map(|video| &video.file.unique_id));
+    let edit = match unique_id {
+        None if *HONOR_RETRACTIONS => {
+            Edit::Retracted(msg.edit_date().map(|date| date.to_rfc3339()))
+        }
+        Some(unique_id) if *UPDATE_EDITED_CAPTIONS => {
+            let mut fields = serde_json::to_value(&msg).unwrap_or_default();
+            for field in REDACT_FIELDS.iter() {
+                redact(&mut fields, &field.split('.').collect::<Vec<_>>());
+            }
+            Edit::Caption {
+                unique_id: unique_id.to_string(),
+                fields,
+                caption_html: msg.html_caption().filter(|_| *PRESERVE_CAPTION_LINKS),
+            }
+        }
+        _ => return,
+    };
+    let root = RUNTIME_CONFIG.read().await.download_dir(msg.chat.id.0);
+    let (chat_id, msg_id) = (msg.chat.id, msg.id);
+    let dirs = day_dirs(&root, msg.date);
+    let result = tokio::task::spawn_blocking(move || {
+        let mut paths = Vec::new();
+        for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+            paths.extend(apply_edit(dir, chat_id, msg_id, &edit)?);
+        }
+        std::io::Result::Ok((paths, edit))
+    })
+    .await;
+    match result.map_err(anyhow::Error::from).and_then(|r| Ok(r?)) {
+        Ok((paths, edit)) => {
+            for path in paths {
+                match edit {
+                    Edit::Retracted(_) => log::info!("Marked {:?} as retracted", path),
+                    Edit::Caption { .. } => log::info!("Updated caption of {:?}", path),
+                }
+            }
+        }
+        Err(e) => log::warn!("Apply edit of message {} error: {:?}", msg_id, e),
+    }
+}
+
+/// Day folders a message sent at `date` can have been archived in: the day it was sent
+/// and the next one, for downloads that finished after midnight or waited in the queue.
+fn day_dirs(root: &Path, date: DateTime<Utc>) -> [PathBuf; 2] {
+    let day = crate::date_in(date, *TIMEZONE);
+    let next = day.succ_opt().unwrap_or(day);
+    [day, next].map(|day| root.join(day.format("%Y-%m-%d").to_string()))
+}
+
+/// Applies `edit` to every file archived from message `msg_id` of `chat_id` under `dir`.
+/// Returns the files whose sidecar changed. Archived media is named
+/// `<name>_<message_id>.<ext>`, so only the sidecars of files named like that are read,
+/// and edits of messages that never carried media cost a folder listing.
+fn apply_edit(
+    dir: &Path,
+    chat_id: ChatId,
+    msg_id: MessageId,
+    edit: &Edit,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut changed = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            changed.extend(apply_edit(&path, chat_id, msg_id, edit)?);
+            continue;
+        }
+        let suffix = format!("_{}", msg_id);
+        if is_sidecar(&path)
+            || !path
+                .file_stem()
+                .is_some_and(|stem| stem.to_string_lossy().ends_with(&suffix))
+        {
+            continue;
+        }
+        let Some(mut sidecar) = read_sidecar(&path) else {
+            continue;
+        };
+        if sidecar["chat"]["id"].as_i64() != Some(chat_id.0)
+            || sidecar["message_id"].as_i64() != Some(msg_id.0 as i64)
+            || sidecar["archive"].get("retracted").is_some()
+        {
+            continue;
+        }
+        if !sidecar["archive"].is_object() {
+            sidecar["archive"] = serde_json::json!({});
+        }
+        match edit {
+            Edit::Retracted(at) => {
+                sidecar["archive"]["retracted"] = serde_json::json!({ "at": at });
+            }
+            Edit::Caption {
+                unique_id,
+                fields,
+                caption_html,
+            } => {
+                if !has_media(&sidecar, unique_id) {
+                    continue;
+                }
+                for field in CAPTION_FIELDS {
+                    set_or_remove(&mut sidecar, field, fields.get(field).cloned());
+                }
+                if *PRESERVE_CAPTION_LINKS {
+                    let caption_html = caption_html.clone().map(Into::into);
+                    set_or_remove(&mut sidecar["archive"], "caption_html", caption_html);
+                }
+            }
+        }
+        write_sidecar(&path, &sidecar)?;
+        changed.push(path);
+    }
+    Ok(changed)
+}
+
+fn set_or_remove(object: &mut serde_json::Value, key: &str, value: Option<serde_json::Value>) {
+    match (object.as_object_mut(), value) {
+        (Some(object), Some(value)) => {
+            object.insert(key.to_string(), value);
+        }
+        (Some(object), None) => {
+            object.remove(key);
+        }
+        (None, _) => {}
+    }
+}
+
+/// Whether the message saved in `sidecar` carries the media with `unique_id`.
+fn has_media(sidecar: &serde_json::Value, unique_id: &str) -> bool {
+    let photo_sizes = sidecar["photo"].as_array().into_iter().flatten();
+    photo_sizes
+        .chain(std::iter::once(&sidecar["video"]))
+        .any(|media| media["file_unique_id"].as_str() == Some(unique_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// A temp archive root and its `2024-01-01` day folder.
+    fn temp_day(name: &str) -> (TempDir, PathBuf) {
+        let root = TempDir::new(name);
+        let dir = root.join("2024-01-01");
+        std::fs::create_dir_all(&dir).unwrap();
+        (root, dir)
+    }
+
+    fn archive(dir: &Path, name: &str, sidecar: serde_json::Value) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, b"media").unwrap();
+        std::fs::write(dir.join(format!("{}.json", name)), sidecar.to_string()).unwrap();
+        path
+    }
+
+    #[test]
+    fn retraction_looks_in_the_message_day_folders() {
+        let date = DateTime::from_timestamp(1704141000, 0).unwrap();
+        let day = crate::date_in(date, *TIMEZONE);
+        let folder = |day: chrono::NaiveDate| Path::new("/archive").join(day.to_string());
+        assert_eq!(
+            day_dirs(Path::new("/archive"), date),
+            [folder(day), folder(day.succ_opt().unwrap())]
+        );
+    }
+
+    #[test]
+    fn retraction_marks_only_the_edited_message() {
+        let (_root, dir) = temp_day("retract");
+        let sidecar = |msg_id| serde_json::json!({ "message_id": msg_id, "chat": { "id": -1001 } });
+        let edited = archive(&dir, "photo_u1_5.jpg", sidecar(5));
+        let other = archive(&dir, "photo_u2_15.jpg", sidecar(15));
+        let elsewhere = archive(
+            &dir,
+            "photo_u3_25.jpg",
+            serde_json::json!({
+                "message_id": 5, "chat": { "id": -1002 }
+            }),
+        );
+
+        let edit = Edit::Retracted(Some(String::from("2024-01-01T21:00:00+00:00")));
+        let changed = apply_edit(&dir, ChatId(-1001), MessageId(5), &edit).unwrap();
+        let [edited, other, elsewhere] =
+            [edited, other, elsewhere].map(|p| read_sidecar(&p).unwrap());
+
+        assert_eq!(changed.len(), 1);
+        assert_eq!(
+            edited["archive"]["retracted"]["at"],
+            "2024-01-01T21:00:00+00:00"
+        );
+        assert!(other.get("archive").is_none());
+        assert!(elsewhere.get("archive").is_none());
+    }
+
+    #[test]
+    fn caption_edit_updates_only_the_sidecar() {
+        let (_root, dir) = temp_day("caption");
+        let photo = serde_json::json!([{ "file_id": "f", "file_unique_id": "u1" }]);
+        let path = archive(
+            &dir,
+            "photo_u1_5.jpg",
+            serde_json::json!({
+                "message_id": 5, "chat": { "id": -1001 }, "photo": photo, "caption": "old",
+                "caption_entities": [{ "type": "bold", "offset": 0, "length": 3 }],
+            }),
+        );
+
+        let caption = |unique_id: &str| Edit::Caption {
+            unique_id: unique_id.into(),
+            fields: serde_json::json!({ "caption": "new", "edit_date": 1704141000 }),
+            caption_html: None,
+        };
+        let replaced = apply_edit(&dir, ChatId(-1001), MessageId(5), &caption("u2")).unwrap();
+        let changed = apply_edit(&dir, ChatId(-1001), MessageId(5), &caption("u1")).unwrap();
+        let sidecar = read_sidecar(&path).unwrap();
+        let media = std::fs::read(&path).unwrap();
+
+        assert!(replaced.is_empty());
+        assert_eq!(changed, [path]);
+        assert_eq!(sidecar["caption"], "new");
+        assert_eq!(sidecar["edit_date"], 1704141000);
+        assert!(sidecar.get("caption_entities").is_none());
+        assert_eq!(media, b"media");
+    }
+}
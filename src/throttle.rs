@@ -0,0 +1,282 @@
+use std::{
+    collections::VecDeque,
+    env,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use tokio::sync::oneshot;
+
+/// Fewest downloads the limit backs off to when Telegram rate-limits the bot.
+static DOWNLOAD_CONCURRENCY_MIN: Lazy<usize> = Lazy::new(|| {
+    env::var("DOWNLOAD_CONCURRENCY_MIN")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(1)
+});
+/// Most downloads running at once. The limit starts at DOWNLOAD_CONCURRENCY_MIN, grows
+/// by one once as many downloads in a row succeeded as it allows, and halves when
+/// Telegram rate-limits the bot. Downloads are not limited when unset.
+static DOWNLOAD_CONCURRENCY_MAX: Lazy<Option<usize>> = Lazy::new(|| {
+    env::var("DOWNLOAD_CONCURRENCY_MAX")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+});
+
+/// Which downloads get a free slot first when several wait for one: `singles`, for
+/// messages outside an album, so a quick reply isn't stuck behind a large album, or
+/// `albums`. In arrival order when unset. Only applies with DOWNLOAD_CONCURRENCY_MAX,
+/// since nothing waits otherwise.
+static DOWNLOAD_PRIORITY: Lazy<Priority> =
+    Lazy::new(
+        || match env::var("DOWNLOAD_PRIORITY").as_deref().map(str::trim) {
+            Ok("singles") => Priority::Singles,
+            Ok("albums") => Priority::Albums,
+            Ok("") | Err(_) => Priority::Arrival,
+            Ok(value) => {
+                log::warn!("Unknown DOWNLOAD_PRIORITY {:?}, use arrival order", value);
+                Priority::Arrival
+            }
+        },
+    );
+
+static LIMITER: Lazy<Option<Mutex<Limiter>>> = Lazy::new(|| {
+    let max = (*DOWNLOAD_CONCURRENCY_MAX)?;
+    let limiter = Limiter::new(*DOWNLOAD_CONCURRENCY_MIN, max, *DOWNLOAD_PRIORITY);
+    Some(Mutex::new(limiter))
+});
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Priority {
+    Arrival,
+    Singles,
+    Albums,
+}
+
+/// Effective download concurrency settings for /config.
+pub fn settings() -> Vec<(&'static str, String)> {
+    let limit = LIMITER
+        .as_ref()
+        .map(|limiter| lock(limiter).limit.to_string());
+    vec![
+        (
+            "DOWNLOAD_CONCURRENCY_MIN",
+            DOWNLOAD_CONCURRENCY_MIN.to_string(),
+        ),
+        (
+            "DOWNLOAD_CONCURRENCY_MAX",
+            format!("{:?}", *DOWNLOAD_CONCURRENCY_MAX),
+        ),
+        ("DOWNLOAD_CONCURRENCY", format!("{:?}", limit)),
+        ("DOWNLOAD_PRIORITY", format!("{:?}", *DOWNLOAD_PRIORITY)),
+    ]
+}
+
+/// Waits for a download slot for a message, which is an album item if `album`. `None`
+/// when DOWNLOAD_CONCURRENCY_MAX is unset.
+pub async fn acquire(album: bool) -> Option<Permit<'static>> {
+    Some(acquire_from(LIMITER.as_ref()?, album).await)
+}
+
+/// Tells the limiter Telegram asked to wait `retry_after`. The limit is halved once per
+/// wait, however many downloads were told so.
+pub fn rate_limited(retry_after: Duration) {
+    if let Some(limiter) = LIMITER.as_ref() {
+        lock(limiter).rate_limited(retry_after, Instant::now());
+    }
+}
+
+fn lock(limiter: &Mutex<Limiter>) -> std::sync::MutexGuard<'_, Limiter> {
+    limiter.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Download slots whose number adapts to rate limits: additive increase, multiplicative
+/// decrease.
+struct Limiter {
+    min: usize,
+    max: usize,
+    limit: usize,
+    running: usize,
+    /// Downloads that succeeded since the limit last changed.
+    successes: usize,
+    /// Rate limits before this belong to the wait the limit was already halved for.
+    quiet_until: Option<Instant>,
+    priority: Priority,
+    /// Tasks waiting for a slot in arrival order, and whether they download an album
+    /// item. A slot is handed over by sending on it.
+    waiters: VecDeque<(bool, oneshot::Sender<()>)>,
+}
+
+impl Limiter {
+    fn new(min: usize, max: usize, priority: Priority) -> Self {
+        let min = min.min(max);
+        Limiter {
+            min,
+            max,
+            limit: min,
+            running: 0,
+            successes: 0,
+            quiet_until: None,
+            priority,
+            waiters: VecDeque::new(),
+        }
+    }
+
+    /// Hands free slots to waiting tasks, skipping the ones that stopped waiting. The
+    /// first waiter of the kind `priority` prefers goes first, then the oldest one.
+    fn wake(&mut self) {
+        let preferred = match self.priority {
+            Priority::Arrival => None,
+            Priority::Singles => Some(false),
+            Priority::Albums => Some(true),
+        };
+        while self.running < self.limit {
+            let next = preferred
+                .and_then(|album| self.waiters.iter().position(|(a, _)| *a == album))
+                .unwrap_or(0);
+            let Some((_, waiter)) = self.waiters.remove(next) else {
+                break;
+            };
+            if waiter.send(()).is_ok() {
+                self.running += 1;
+            }
+        }
+    }
+
+    fn succeeded(&mut self) {
+        self.successes += 1;
+        if self.successes >= self.limit && self.limit < self.max {
+            self.limit += 1;
+            self.successes = 0;
+            log::info!("Download concurrency raised to {}", self.limit);
+            self.wake();
+        }
+    }
+
+    /// Halves the limit, down to `min`. Downloads above the new limit keep running, and
+    /// no new one starts until enough of them finished.
+    fn rate_limited(&mut self, retry_after: Duration, now: Instant) {
+        if self.quiet_until.is_some_and(|until| now < until) {
+            return;
+        }
+        self.quiet_until = Some(now + retry_after);
+        self.successes = 0;
+        let limit = (self.limit / 2).max(self.min);
+        if limit != self.limit {
+            self.limit = limit;
+            log::info!(
+                "Download concurrency lowered to {} after a rate limit",
+                limit
+            );
+        }
+    }
+}
+
+async fn acquire_from(limiter: &Mutex<Limiter>, album: bool) -> Permit<'_> {
+    let waiting = {
+        let mut state = lock(limiter);
+        if state.running < state.limit {
+            state.running += 1;
+            None
+        } else {
+            let (sender, receiver) = oneshot::channel();
+            state.waiters.push_back((album, sender));
+            Some(receiver)
+        }
+    };
+    if let Some(receiver) = waiting {
+        // The sender is only dropped with the limiter, which lives as long as the bot.
+        let _ = receiver.await;
+    }
+    Permit { limiter }
+}
+
+/// A running download's slot, released on drop.
+pub struct Permit<'a> {
+    limiter: &'a Mutex<Limiter>,
+}
+
+impl Permit<'_> {
+    /// Releases the slot, counting the download towards a higher limit if it succeeded.
+    pub fn finish(self, succeeded: bool) {
+        if succeeded {
+            lock(self.limiter).succeeded();
+        }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut state = lock(self.limiter);
+        state.running -= 1;
+        state.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rate_limits_reduce_the_limit() {
+        let limiter = Mutex::new(Limiter::new(1, 8, Priority::Arrival));
+        for _ in 0..6 {
+            acquire_from(&limiter, false).await.finish(true);
+        }
+        assert_eq!(lock(&limiter).limit, 4);
+
+        let permits = [
+            acquire_from(&limiter, false).await,
+            acquire_from(&limiter, false).await,
+            acquire_from(&limiter, false).await,
+        ];
+        let now = Instant::now();
+        let wait = Duration::from_secs(5);
+        // Every download told to wait reports it, but the limit halves once per wait.
+        for _ in &permits {
+            lock(&limiter).rate_limited(wait, now);
+        }
+        assert_eq!(lock(&limiter).limit, 2);
+        let waiting =
+            tokio::time::timeout(Duration::from_millis(20), acquire_from(&limiter, false));
+        assert!(waiting.await.is_err());
+
+        drop(permits);
+        lock(&limiter).rate_limited(wait, now + wait);
+        lock(&limiter).rate_limited(wait, now + wait * 2);
+        assert_eq!(lock(&limiter).limit, 1);
+        let state = lock(&limiter);
+        assert_eq!(state.running, 0);
+        assert!(state.waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn single_messages_go_before_album_items() {
+        let limiter = Arc::new(Mutex::new(Limiter::new(1, 1, Priority::Singles)));
+        let permit = acquire_from(&limiter, false).await;
+        let (sender, mut started) = tokio::sync::mpsc::unbounded_channel();
+        let waiting = [("album 1", true), ("album 2", true), ("single", false)];
+        for (queued, (name, album)) in waiting.into_iter().enumerate() {
+            let (slots, sender) = (limiter.clone(), sender.clone());
+            tokio::spawn(async move {
+                let _permit = acquire_from(&slots, album).await;
+                sender.send(name).unwrap();
+            });
+            // Queue them in this order.
+            while lock(&limiter).waiters.len() <= queued {
+                tokio::task::yield_now().await;
+            }
+        }
+        drop(permit);
+        let mut order = vec![];
+        for _ in 0..3 {
+            order.push(started.recv().await.unwrap());
+        }
+        assert_eq!(order, ["single", "album 1", "album 2"]);
+    }
+}
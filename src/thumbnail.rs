@@ -0,0 +1,126 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Result, bail};
+use image::imageops::FilterType;
+use once_cell::sync::Lazy;
+
+use crate::{is_sidecar, read_sidecar};
+
+/// Longest side of generated thumbnails, in pixels.
+pub static THUMBNAIL_SIZE: Lazy<u32> = Lazy::new(|| {
+    env::var("THUMBNAIL_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(320)
+});
+
+const THUMBNAIL_DIR: &str = "thumbnails";
+
+#[derive(Default)]
+pub struct ThumbnailStats {
+    pub created: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Creates `thumbnails/<name>.jpg` next to every archived photo and video under `dir`.
+/// Existing thumbnails are kept, so running it again only fills in new files.
+pub fn generate(dir: &Path) -> std::io::Result<ThumbnailStats> {
+    let mut stats = ThumbnailStats::default();
+    walk(dir, &mut stats)?;
+    Ok(stats)
+}
+
+fn walk(dir: &Path, stats: &mut ThumbnailStats) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if path.file_name().is_some_and(|name| name != THUMBNAIL_DIR) {
+                walk(&path, stats)?;
+            }
+            continue;
+        }
+        if is_sidecar(&path) {
+            continue;
+        }
+        // Originals, partial files and the like have no sidecar of their own.
+        let Some(sidecar) = read_sidecar(&path) else {
+            continue;
+        };
+        if sidecar["archive"].get("encryption").is_some() {
+            continue;
+        }
+        let is_photo = sidecar.get("photo").is_some();
+        if !is_photo && sidecar.get("video").is_none() {
+            continue;
+        }
+
+        let thumbnail = thumbnail_path(&path);
+        if thumbnail.exists() {
+            stats.skipped += 1;
+            continue;
+        }
+        if let Some(parent) = thumbnail.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let result = if is_photo {
+            photo_thumbnail(&path, &thumbnail)
+        } else {
+            video_thumbnail(&path, &thumbnail)
+        };
+        match result {
+            Ok(()) => stats.created += 1,
+            Err(e) => {
+                log::warn!("Create thumbnail of {:?} error: {:?}", path, e);
+                let _ = std::fs::remove_file(&thumbnail);
+                stats.failed += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn thumbnail_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".jpg");
+    path.with_file_name(THUMBNAIL_DIR).join(name)
+}
+
+fn photo_thumbnail(path: &Path, thumbnail: &Path) -> Result<()> {
+    let size = *THUMBNAIL_SIZE;
+    image::open(path)?
+        .resize(size, size, FilterType::Triangle)
+        .to_rgb8()
+        .save(thumbnail)?;
+    Ok(())
+}
+
+/// Grabs the first frame with ffmpeg, scaled to fit THUMBNAIL_SIZE.
+fn video_thumbnail(path: &Path, thumbnail: &Path) -> Result<()> {
+    let size = *THUMBNAIL_SIZE;
+    let output = Command::new("ffmpeg")
+        .arg("-y")
+        .args(["-loglevel", "error", "-i"])
+        .arg(path)
+        .args(["-frames:v", "1", "-vf"])
+        .arg(format!(
+            "scale={}:{}:force_original_aspect_ratio=decrease",
+            size, size
+        ))
+        .arg(thumbnail)
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "ffmpeg exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
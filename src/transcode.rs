@@ -0,0 +1,85 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::{Result, bail};
+use once_cell::sync::Lazy;
+use tokio::process::Command;
+
+use crate::original_path;
+
+/// Transcode downloaded videos into H.264/AAC MP4 with ffmpeg.
+static TRANSCODE_VIDEOS: Lazy<bool> = Lazy::new(|| crate::env_flag("TRANSCODE_VIDEOS"));
+/// ffmpeg output arguments, placed between the input and the output file.
+static TRANSCODE_ARGS: Lazy<Vec<String>> = Lazy::new(|| {
+    env::var("TRANSCODE_ARGS")
+        .unwrap_or(String::from(
+            "-c:v libx264 -preset medium -crf 23 -c:a aac -movflags +faststart",
+        ))
+        .split_whitespace()
+        .map(String::from)
+        .collect()
+});
+/// Keep the downloaded video as `<name>.original.<ext>` next to the transcoded one.
+static KEEP_ORIGINAL: Lazy<bool> = Lazy::new(|| crate::env_flag("KEEP_ORIGINAL"));
+static FFMPEG_AVAILABLE: Lazy<bool> = Lazy::new(|| {
+    let available = std::process::Command::new("ffmpeg")
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !available {
+        log::warn!("TRANSCODE_VIDEOS is set but ffmpeg is not available, skip transcoding");
+    }
+    available
+});
+
+/// Effective transcode settings for /config.
+pub fn settings() -> Vec<(&'static str, String)> {
+    vec![
+        ("TRANSCODE_VIDEOS", TRANSCODE_VIDEOS.to_string()),
+        ("TRANSCODE_ARGS", TRANSCODE_ARGS.join(" ")),
+        ("KEEP_ORIGINAL", KEEP_ORIGINAL.to_string()),
+    ]
+}
+
+pub fn enabled() -> bool {
+    *TRANSCODE_VIDEOS && *FFMPEG_AVAILABLE
+}
+
+/// Transcodes the video at `path` and returns the path of the MP4 that replaces it
+/// in the archive.
+pub async fn transcode_video(path: &Path) -> Result<PathBuf> {
+    let output = path.with_extension("mp4");
+    let transcoding = path.with_extension("transcoding.mp4");
+    log::debug!("Transcoding video: {:?}", path);
+    let result = Command::new("ffmpeg")
+        .arg("-y")
+        .args(["-loglevel", "error", "-i"])
+        .arg(path)
+        .args(TRANSCODE_ARGS.iter())
+        .arg(&transcoding)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    if !result.status.success() {
+        let _ = tokio::fs::remove_file(&transcoding).await;
+        bail!(
+            "ffmpeg exited with {}: {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+
+    if *KEEP_ORIGINAL {
+        tokio::fs::rename(path, original_path(path)).await?;
+    } else if output != path {
+        tokio::fs::remove_file(path).await?;
+    }
+    tokio::fs::rename(&transcoding, &output).await?;
+    log::info!("Transcoded video {:?} to {:?}", path, &output);
+    Ok(output)
+}
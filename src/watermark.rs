@@ -0,0 +1,98 @@
+use std::{env, path::Path};
+
+use ab_glyph::{FontVec, PxScale};
+use anyhow::{Context, Result};
+use image::{DynamicImage, Rgb, imageops};
+use imageproc::drawing::{draw_text_mut, text_size};
+use once_cell::sync::Lazy;
+
+/// Text stamped onto saved photos, rendered with the TTF/OTF font at WATERMARK_FONT.
+static WATERMARK_TEXT: Lazy<Option<(String, FontVec)>> = Lazy::new(|| {
+    let text = env::var("WATERMARK_TEXT").ok()?;
+    let Ok(path) = env::var("WATERMARK_FONT") else {
+        log::warn!("WATERMARK_TEXT is set without WATERMARK_FONT, skip text watermark");
+        return None;
+    };
+    std::fs::read(&path)
+        .map_err(anyhow::Error::from)
+        .and_then(|data| FontVec::try_from_vec(data).map_err(anyhow::Error::from))
+        .inspect_err(|e| log::warn!("Load watermark font {} error: {:?}", path, e))
+        .ok()
+        .map(|font| (text, font))
+});
+/// Image (usually a transparent PNG) overlaid onto the bottom-right corner of saved photos.
+static WATERMARK_IMAGE: Lazy<Option<DynamicImage>> = Lazy::new(|| {
+    let path = env::var("WATERMARK_IMAGE").ok()?;
+    image::open(&path)
+        .inspect_err(|e| log::warn!("Load watermark image {} error: {:?}", path, e))
+        .ok()
+});
+/// Keep the untouched photo next to the watermarked one as `<name>.original.<ext>`.
+static WATERMARK_KEEP_ORIGINAL: Lazy<bool> =
+    Lazy::new(|| crate::env_flag("WATERMARK_KEEP_ORIGINAL"));
+
+const MARGIN: u32 = 16;
+
+/// Effective watermark settings for /config.
+pub fn settings() -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "WATERMARK_TEXT",
+            format!("{:?}", WATERMARK_TEXT.as_ref().map(|(text, _)| text)),
+        ),
+        ("WATERMARK_IMAGE", WATERMARK_IMAGE.is_some().to_string()),
+        (
+            "WATERMARK_KEEP_ORIGINAL",
+            WATERMARK_KEEP_ORIGINAL.to_string(),
+        ),
+    ]
+}
+
+pub fn enabled() -> bool {
+    WATERMARK_TEXT.is_some() || WATERMARK_IMAGE.is_some()
+}
+
+/// Stamps the configured watermark onto the photo at `path` in place.
+/// Returns `false` when the file could not be decoded as an image and was left untouched.
+pub fn watermark_photo(path: &Path) -> Result<bool> {
+    let photo = match image::open(path) {
+        Ok(photo) => photo,
+        Err(e) => {
+            log::warn!("Decode {:?} for watermark error: {:?}", path, e);
+            return Ok(false);
+        }
+    };
+    if *WATERMARK_KEEP_ORIGINAL {
+        let original = crate::original_path(path);
+        std::fs::copy(path, &original)
+            .with_context(|| format!("Keep original photo error: {}", original.display()))?;
+    }
+
+    let mut photo = photo.to_rgba8();
+    if let Some(mark) = WATERMARK_IMAGE.as_ref() {
+        // Keep the mark within a quarter of the photo width.
+        let max_width = (photo.width() / 4).max(1);
+        let mark = if mark.width() > max_width {
+            mark.resize(max_width, u32::MAX, imageops::FilterType::Triangle)
+        } else {
+            mark.clone()
+        };
+        let x = photo.width().saturating_sub(mark.width() + MARGIN);
+        let y = photo.height().saturating_sub(mark.height() + MARGIN);
+        imageops::overlay(&mut photo, &mark.to_rgba8(), x.into(), y.into());
+    }
+    let mut photo = DynamicImage::ImageRgba8(photo).to_rgb8();
+    if let Some((text, font)) = WATERMARK_TEXT.as_ref() {
+        let scale = PxScale::from((photo.height() / 20).max(12) as f32);
+        let (width, height) = text_size(scale, font, text);
+        let x = photo.width().saturating_sub(width + MARGIN) as i32;
+        let y = photo.height().saturating_sub(height + MARGIN) as i32;
+        // A dark shadow keeps the text readable on light photos.
+        draw_text_mut(&mut photo, Rgb([0, 0, 0]), x + 2, y + 2, scale, font, text);
+        draw_text_mut(&mut photo, Rgb([255, 255, 255]), x, y, scale, font, text);
+    }
+    photo
+        .save(path)
+        .with_context(|| format!("Save watermarked photo error: {}", path.display()))?;
+    Ok(true)
+}
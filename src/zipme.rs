@@ -0,0 +1,257 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use once_cell::sync::Lazy;
+use teloxide::{
+    prelude::*,
+    sugar::request::RequestReplyExt,
+    types::{InputFile, Message},
+};
+use tokio::sync::Mutex;
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    LAST_FILES, commands::MAX_UPLOAD_BYTES, read_sidecar, retention::related_paths, topic_id,
+};
+
+/// Remember what each chat sends, so /zipme can send it back as zip files.
+pub static ZIP_SESSIONS: Lazy<bool> = Lazy::new(|| crate::env_flag("ZIP_SESSIONS"));
+/// Delete the files of a session from the archive once /zipme has sent them, with their
+/// sidecars, kept originals and thumbnails.
+pub static ZIP_DISCARD: Lazy<bool> = Lazy::new(|| crate::env_flag("ZIP_DISCARD"));
+
+/// Room left in each zip for headers, so it stays under the upload limit.
+const ZIP_OVERHEAD_BYTES: u64 = 1024 * 1024;
+
+/// Files archived per chat since its last /zipme.
+static SESSIONS: Lazy<Mutex<HashMap<ChatId, Vec<PathBuf>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub async fn collect(chat_id: ChatId, path: PathBuf) {
+    if *ZIP_SESSIONS {
+        SESSIONS.lock().await.entry(chat_id).or_default().push(path);
+    }
+}
+
+/// Zips the session of the chat `msg` came from and sends it back, split into several
+/// zips when it exceeds the upload limit. Returns the reply text. When a zip can't be
+/// written or sent, its files and those of the later zips stay in the session for the
+/// next /zipme.
+pub async fn send_session(bot: &Bot, msg: &Message) -> ResponseResult<String> {
+    if !*ZIP_SESSIONS {
+        return Ok(String::from("未启用 ZIP_SESSIONS"));
+    }
+    let files = SESSIONS
+        .lock()
+        .await
+        .remove(&msg.chat.id)
+        .unwrap_or_default();
+    if files.is_empty() {
+        return Ok(String::from("本次会话没有文件"));
+    }
+
+    let budget = MAX_UPLOAD_BYTES - ZIP_OVERHEAD_BYTES;
+    let session = files.clone();
+    let (parts, skipped) =
+        match tokio::task::spawn_blocking(move || split_parts(session, budget)).await {
+            Ok(split) => split,
+            Err(e) => {
+                log::warn!("Split session error: {:?}", e);
+                let mut sessions = SESSIONS.lock().await;
+                sessions.entry(msg.chat.id).or_default().splice(0..0, files);
+                return Ok(String::from("打包失败"));
+            }
+        };
+    let mut sent_parts = 0;
+    let mut failure = None;
+    for (i, part) in parts.iter().enumerate() {
+        if let Err(e) = send_part(bot, msg, i + 1, part).await {
+            failure = Some(e);
+            break;
+        }
+        sent_parts += 1;
+    }
+    let (sent, unsent) = parts.split_at(sent_parts);
+
+    if *ZIP_DISCARD {
+        discard(sent.iter().flatten()).await;
+    }
+    if let Some(e) = failure {
+        // Keep what wasn't sent for the next /zipme, ahead of files archived meanwhile.
+        let mut sessions = SESSIONS.lock().await;
+        let session = sessions.entry(msg.chat.id).or_default();
+        session.splice(0..0, unsent.iter().flatten().cloned());
+        return match e {
+            PartError::Zip(e) => {
+                log::warn!("Write zip error: {:?}", e);
+                Ok(String::from("打包失败"))
+            }
+            PartError::Send(e) => Err(e),
+        };
+    }
+    let sent_files: usize = sent.iter().map(Vec::len).sum();
+    let mut reply = format!("已发送 {} 个文件，共 {} 个压缩包", sent_files, parts.len());
+    if skipped.unsendable > 0 {
+        reply.push_str(&format!("，跳过 {} 个（已加密或过大）", skipped.unsendable));
+    }
+    if skipped.missing > 0 {
+        reply.push_str(&format!(
+            "，{} 个已不在原位置（已重命名或删除）",
+            skipped.missing
+        ));
+    }
+    Ok(reply)
+}
+
+enum PartError {
+    Zip(anyhow::Error),
+    Send(teloxide::RequestError),
+}
+
+/// Zips `part` as the `number`th zip of the session and sends it.
+async fn send_part(
+    bot: &Bot,
+    msg: &Message,
+    number: usize,
+    part: &[PathBuf],
+) -> Result<(), PartError> {
+    let zip_path =
+        std::env::temp_dir().join(format!("eatlink_{}_{}_{}.zip", msg.chat.id, msg.id, number));
+    let (files, zip) = (part.to_vec(), zip_path.clone());
+    let written = tokio::task::spawn_blocking(move || write_zip(&zip, &files)).await;
+    if let Err(e) = written.map_err(anyhow::Error::from).and_then(|r| Ok(r?)) {
+        let _ = tokio::fs::remove_file(&zip_path).await;
+        return Err(PartError::Zip(
+            e.context(format!("Write zip {:?}", &zip_path)),
+        ));
+    }
+    let mut request = bot
+        .send_document(msg.chat.id, InputFile::file(&zip_path))
+        .reply_to(msg.id);
+    request.message_thread_id = topic_id(msg);
+    let result = request.await;
+    let _ = tokio::fs::remove_file(&zip_path).await;
+    result.map(|_| ()).map_err(PartError::Send)
+}
+
+/// Deletes sent files from the archive with everything saved alongside them, and
+/// forgets them as the latest file of their chat.
+async fn discard(files: impl Iterator<Item = &PathBuf>) {
+    let files: Vec<&PathBuf> = files.collect();
+    for file in &files {
+        for path in related_paths(file) {
+            if path.exists()
+                && let Err(e) = tokio::fs::remove_file(&path).await
+            {
+                log::warn!("Discard {:?} error: {:?}", &path, e);
+            }
+        }
+    }
+    LAST_FILES
+        .lock()
+        .await
+        .retain(|_, path| !files.contains(&&*path));
+}
+
+/// Files [`split_parts`] left out of the zips.
+#[derive(Debug, Default, PartialEq)]
+struct Skipped {
+    /// Encrypted, or too big for a zip on their own.
+    unsendable: usize,
+    /// No longer under their archived name, like after /rename.
+    missing: usize,
+}
+
+/// Groups `files` into zips of at most `budget` bytes, leaving out the ones it can't
+/// send. Reads the disk, so it runs on a blocking thread.
+fn split_parts(files: Vec<PathBuf>, budget: u64) -> (Vec<Vec<PathBuf>>, Skipped) {
+    let (mut parts, mut part, mut part_size) = (Vec::new(), Vec::new(), 0);
+    let mut skipped = Skipped::default();
+    for path in files {
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            skipped.missing += 1;
+            continue;
+        };
+        let size = metadata.len();
+        let encrypted = read_sidecar(&path)
+            .is_some_and(|sidecar| sidecar["archive"].get("encryption").is_some());
+        if encrypted || size > budget {
+            skipped.unsendable += 1;
+            continue;
+        }
+        if part_size + size > budget {
+            parts.push(std::mem::take(&mut part));
+            part_size = 0;
+        }
+        part_size += size;
+        part.push(path);
+    }
+    if !part.is_empty() {
+        parts.push(part);
+    }
+    (parts, skipped)
+}
+
+/// Media is already compressed, so files are stored as they are.
+fn write_zip(zip: &Path, files: &[PathBuf]) -> io::Result<()> {
+    let mut writer = ZipWriter::new(BufWriter::new(File::create(zip)?));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    for path in files {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        writer.start_file(name, options)?;
+        io::copy(&mut BufReader::new(File::open(path)?), &mut writer)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sidecar_paths;
+
+    #[test]
+    fn split_parts_fills_each_zip_up_to_budget() {
+        let dir = crate::testing::TempDir::new("split");
+        let file = |name: &str, size: usize| {
+            let path = dir.join(name);
+            std::fs::write(&path, vec![0; size]).unwrap();
+            path
+        };
+        let encrypted = file("encrypted.jpg", 1);
+        std::fs::write(
+            sidecar_paths(&encrypted)[0].clone(),
+            r#"{"archive":{"encryption":{}}}"#,
+        )
+        .unwrap();
+        let files = vec![
+            file("a.jpg", 40),
+            file("b.jpg", 60),
+            file("c.jpg", 30),
+            file("big.mp4", 101),
+            encrypted,
+            dir.join("renamed.jpg"),
+            file("d.jpg", 70),
+        ];
+
+        let (parts, skipped) = split_parts(files, 100);
+
+        let names: Vec<Vec<_>> = parts
+            .iter()
+            .map(|part| part.iter().map(|path| path.file_name().unwrap()).collect())
+            .collect();
+        assert_eq!(names, [vec!["a.jpg", "b.jpg"], vec!["c.jpg", "d.jpg"]]);
+        assert_eq!(
+            skipped,
+            Skipped {
+                unsendable: 2,
+                missing: 1
+            }
+        );
+    }
+}